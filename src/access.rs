@@ -0,0 +1,647 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use discord::model::{Permissions, ServerId};
+use discord::model::permissions::{ADMINISTRATOR, BAN_MEMBERS, KICK_MEMBERS,
+                                  MANAGE_CHANNELS, MANAGE_MESSAGES,
+                                  MANAGE_NICKNAMES, MANAGE_ROLES,
+                                  MANAGE_SERVER, MENTION_EVERYONE};
+use bot::{Connection, Message};
+use command::{Args, ArgKind, Command, Router};
+use error::Result;
+use executor::PluginExecutor;
+use plugin::Plugin;
+use store;
+
+/// Where per-server overrides are kept between runs.
+const FILE_PATH: &'static str = "access.json";
+/// The permissions an `Access` can name, as written in `!access`.
+const PERMISSIONS: &'static [(&'static str, Permissions)] = &[
+    ("administrator", ADMINISTRATOR),
+    ("manage_server", MANAGE_SERVER),
+    ("manage_channels", MANAGE_CHANNELS),
+    ("manage_roles", MANAGE_ROLES),
+    ("manage_messages", MANAGE_MESSAGES),
+    ("manage_nicknames", MANAGE_NICKNAMES),
+    ("kick_members", KICK_MEMBERS),
+    ("ban_members", BAN_MEMBERS),
+    ("mention_everyone", MENTION_EVERYONE)
+];
+const LEVELS: &'static str = "`everyone`, `roles <name, ...>`, \
+                              `permission <name>`, `server-owner` or \
+                              `bot-owner`";
+
+/// Who may use a plugin or command. Each level lets in the server's owner
+/// and the bot's owners as well, except that only the bot's owners pass
+/// `BotOwner`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Access {
+    Everyone,
+    /// Members with any of these roles, by name.
+    Roles(Vec<String>),
+    /// Members with this permission in the channel, or administrators.
+    Permission(Permissions),
+    ServerOwner,
+    /// The `owners` in the config.
+    BotOwner
+}
+
+impl Access {
+    /// Parses a level as written in `!access`: `everyone`, `roles Mod, DJ`,
+    /// `permission manage_messages`, `server-owner` or `bot-owner`.
+    pub fn parse(text: &str) -> ::std::result::Result<Access, String> {
+        let text = text.trim();
+        let (level, rest) = match text.find(char::is_whitespace) {
+            Some(i) => (&text[..i], text[i..].trim()),
+            None => (text, "")
+        };
+        match (level.to_lowercase().as_str(), rest.is_empty()) {
+            ("everyone", true) => Ok(Access::Everyone),
+            ("server-owner", true) => Ok(Access::ServerOwner),
+            ("bot-owner", true) => Ok(Access::BotOwner),
+            ("role", false) | ("roles", false) => {
+                let roles = rest.split(',')
+                    .map(|r| r.trim().trim_left_matches('@').to_string())
+                    .filter(|r| !r.is_empty())
+                    .collect::<Vec<String>>();
+                if roles.is_empty() {
+                    return Err("No roles given".to_string())
+                }
+                Ok(Access::Roles(roles))
+            }
+            ("permission", false) => {
+                let name = rest.to_lowercase()
+                    .replace(|c: char| c == ' ' || c == '-', "_");
+                PERMISSIONS.iter()
+                    .find(|&&(n, _)| n == name)
+                    .map(|&(_, p)| Access::Permission(p))
+                    .ok_or_else(|| {
+                        let names = PERMISSIONS.iter()
+                            .map(|&(n, _)| n)
+                            .collect::<Vec<&str>>();
+                        format!("There is no `{}` permission. Use one of: {}",
+                                rest, names.join(", "))
+                    })
+            }
+            _ => Err(format!("Expected {}", LEVELS))
+        }
+    }
+
+    /// Writes the level the way `parse` reads it.
+    pub fn spec(&self) -> String {
+        match *self {
+            Access::Everyone => "everyone".to_string(),
+            Access::Roles(ref roles) => format!("roles {}", roles.join(", ")),
+            Access::Permission(p) => {
+                format!("permission {}", permission_name(p).unwrap_or("?"))
+            }
+            Access::ServerOwner => "server-owner".to_string(),
+            Access::BotOwner => "bot-owner".to_string()
+        }
+    }
+
+    /// Whether the author of `msg` is let in.
+    pub fn allows(&self, msg: &Message) -> bool {
+        if msg.is_owner() { return true }
+        match *self {
+            Access::Everyone => true,
+            Access::BotOwner => false,
+            _ if msg.is_server_owner() => true,
+            Access::ServerOwner => false,
+            Access::Roles(ref roles) => {
+                msg.roles().iter().any(|role| {
+                    roles.iter().any(|r| r.to_lowercase() ==
+                                     role.name.to_lowercase())
+                })
+            }
+            Access::Permission(p) => msg.permissions().map_or(false, |have| {
+                have.contains(ADMINISTRATOR) || have.contains(p)
+            })
+        }
+    }
+}
+
+/// Describes who is let in, e.g. "members with the Manage Messages
+/// permission".
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Access::Everyone => f.write_str("everyone"),
+            Access::Roles(ref roles) if roles.len() == 1 => {
+                write!(f, "members with the {} role", roles[0])
+            }
+            Access::Roles(ref roles) => {
+                write!(f, "members with one of the roles {}", roles.join(", "))
+            }
+            Access::Permission(p) => {
+                let name = permission_name(p).unwrap_or("right");
+                let label = name.split('_')
+                    .map(capitalize)
+                    .collect::<Vec<String>>();
+                write!(f, "members with the {} permission", label.join(" "))
+            }
+            Access::ServerOwner => f.write_str("the server owner"),
+            Access::BotOwner => f.write_str("the bot's owners")
+        }
+    }
+}
+
+fn permission_name(permission: Permissions) -> Option<&'static str> {
+    PERMISSIONS.iter().find(|&&(_, p)| p == permission).map(|&(n, _)| n)
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new()
+    }
+}
+
+/// `AccessRules` decides who may use each plugin and command. The level a
+/// command declares applies, or the plugin's if the command leaves it at
+/// `Everyone`. Server admins can override either with `!access`, except
+/// where only the bot's owners are let in. Overrides are kept under the
+/// plugin's name, or `plugin:command` for one command, and saved to
+/// `access.json` whenever they change.
+pub struct AccessRules {
+    servers: RwLock<HashMap<ServerId, BTreeMap<String, Access>>>,
+    path: PathBuf
+}
+
+impl AccessRules {
+    /// Loads the overrides saved by an earlier run, if any.
+    pub fn load() -> AccessRules {
+        AccessRules::load_from(PathBuf::from(FILE_PATH))
+    }
+
+    /// Like `load`, but keeps the overrides at `path`.
+    pub fn load_from(path: PathBuf) -> AccessRules {
+        let servers = match read_servers(&path) {
+            Ok(servers) => servers,
+            Err(err) => {
+                println!("[Warning] Failed to load {}: {}", path.display(),
+                         err);
+                HashMap::new()
+            }
+        };
+        AccessRules {
+            servers: RwLock::new(servers),
+            path: path
+        }
+    }
+
+    /// A server's override for a plugin, or for a command by its
+    /// `plugin:command` key.
+    pub fn get(&self, server: ServerId, name: &str) -> Option<Access> {
+        self.servers.read().unwrap().get(&server)
+            .and_then(|names| names.get(name).cloned())
+    }
+
+    /// Overrides who may use a plugin or command in a server, or goes back
+    /// to the level it declares for `None`, and saves the change. `name` is
+    /// as for `get`.
+    pub fn set(&self, server: ServerId, name: &str, access: Option<Access>)
+        -> io::Result<()> {
+        let mut servers = self.servers.write().unwrap();
+        match access {
+            Some(access) => {
+                servers.entry(server).or_insert_with(BTreeMap::new)
+                    .insert(name.to_string(), access);
+            }
+            None => {
+                let empty = match servers.get_mut(&server) {
+                    Some(names) => {
+                        names.remove(name);
+                        names.is_empty()
+                    }
+                    None => false
+                };
+                if empty {
+                    servers.remove(&server);
+                }
+            }
+        }
+        let saved = servers.iter()
+            .map(|(&id, names)| {
+                let names = names.iter()
+                    .map(|(name, access)| (name.clone(), access.spec()))
+                    .collect::<BTreeMap<String, String>>();
+                (id, names)
+            })
+            .collect::<HashMap<ServerId, BTreeMap<String, String>>>();
+        store::save(&self.path, &saved)
+    }
+
+    /// Who may use `command` of `plugin` in a server, or `plugin` as a
+    /// whole for `None`.
+    pub fn required(&self, server: Option<ServerId>, plugin: &Plugin,
+                    command: Option<&Command>) -> Access {
+        let declared = match command {
+            Some(c) if c.access != Access::Everyone => c.access.clone(),
+            _ => plugin.access()
+        };
+        let server = match server {
+            Some(server) if declared != Access::BotOwner => server,
+            _ => return declared
+        };
+        command.and_then(|c| self.get(server, &command_key(plugin, c)))
+            .or_else(|| self.get(server, plugin.name()))
+            .unwrap_or(declared)
+    }
+
+    /// Whether the author of `msg` may use `command` of `plugin`.
+    pub fn allows(&self, plugin: &Plugin, command: Option<&Command>,
+                  msg: &Message) -> bool {
+        self.required(msg.server_id(), plugin, command).allows(msg)
+    }
+}
+
+/// The name a command's overrides are kept under. Commands are named after
+/// their plugin, so that `meme` the plugin and `meme` its command are told
+/// apart, as are commands of the same name in different plugins.
+fn command_key(plugin: &Plugin, command: &Command) -> String {
+    format!("{}:{}", plugin.name(), command.name)
+}
+
+/// Loads the overrides saved by `AccessRules::set`, which are kept as level
+/// specs.
+fn read_servers(path: &PathBuf)
+    -> io::Result<HashMap<ServerId, BTreeMap<String, Access>>> {
+    let saved = store::load::<BTreeMap<String, String>>(path, "access")?;
+    let mut servers = HashMap::new();
+    for (id, names) in saved {
+        let mut rules = BTreeMap::new();
+        for (name, spec) in names {
+            match Access::parse(&spec) {
+                Ok(access) => { rules.insert(name, access); }
+                Err(err) => println!("[Warning] Ignoring access for {}: {}",
+                                     name, err)
+            }
+        }
+        servers.insert(id, rules);
+    }
+    Ok(servers)
+}
+
+/// The built-in `!access` command, which lets server admins change who may
+/// use a plugin or command in their server.
+pub struct AccessPlugin {
+    rules: Arc<AccessRules>,
+    plugins: Weak<RwLock<Vec<PluginExecutor>>>,
+    commands: Router<AccessPlugin>
+}
+
+impl AccessPlugin {
+    pub fn with_rules(rules: Arc<AccessRules>,
+                      plugins: Weak<RwLock<Vec<PluginExecutor>>>)
+        -> AccessPlugin {
+        let admin = Access::Permission(MANAGE_SERVER);
+        AccessPlugin {
+            rules: rules,
+            plugins: plugins,
+            commands: Router::new()
+                .add(Command::new("access")
+                     .arg("name", ArgKind::Word)
+                     .description("Shows who may use a plugin or command")
+                     .access(admin.clone()),
+                     AccessPlugin::show)
+                .add(Command::new("access")
+                     .arg("name", ArgKind::Word)
+                     .arg("reset", ArgKind::Literal("reset"))
+                     .description("Goes back to the default access")
+                     .access(admin.clone()),
+                     AccessPlugin::reset)
+                .add(Command::new("access")
+                     .arg("name", ArgKind::Word)
+                     .arg("level", ArgKind::Rest)
+                     .description("Changes who may use a plugin or command \
+                                   in this server")
+                     .access(admin),
+                     AccessPlugin::change)
+        }
+    }
+
+    fn show(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let name = match self.target(args, msg, conn)? {
+            Some(name) => name,
+            None => return Ok(())
+        };
+        let server = msg.server_id().unwrap();
+        let text = match self.rules.get(server, &name) {
+            Some(access) => {
+                format!("`{}` is limited to {} in this server", name, access)
+            }
+            None => format!("`{}` has its default access", name)
+        };
+        conn.reply(msg, &text)?;
+        Ok(())
+    }
+
+    fn reset(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let name = match self.target(args, msg, conn)? {
+            Some(name) => name,
+            None => return Ok(())
+        };
+        self.update(msg, conn, &name, None)
+    }
+
+    fn change(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let name = match self.target(args, msg, conn)? {
+            Some(name) => name,
+            None => return Ok(())
+        };
+        match Access::parse(args.str("level").unwrap()) {
+            Ok(access) => self.update(msg, conn, &name, Some(access)),
+            Err(err) => {
+                conn.reply(msg, &err)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Finds the plugin or command named in `args`, and checks that its
+    /// access may be changed. Replies and returns `None` if not, or else
+    /// the name its overrides are kept under.
+    ///
+    /// A plugin's name wins over a command of the same name. Such a command
+    /// can still be named as `plugin:command`.
+    fn target(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<Option<String>> {
+        if msg.server_id().is_none() {
+            conn.reply(msg, "Access can only be changed in a server")?;
+            return Ok(None)
+        }
+        let name = args.str("name").unwrap().trim_left_matches(msg.prefix());
+        let plugins = match self.plugins.upgrade() {
+            Some(plugins) => plugins,
+            None => return Ok(None)
+        };
+        let plugins = plugins.read().unwrap();
+        let plugins = plugins.iter().map(|e| e.plugin()).collect::<Vec<_>>();
+
+        let command_of = |plugin: &Plugin, command: &str| {
+            plugin.commands().into_iter().find(|c| c.is_named(command))
+                .map(|c| {
+                    let access = self.rules.required(None, plugin, Some(&c));
+                    (plugin.name(), command_key(plugin, &c), access)
+                })
+        };
+        let found = match name.find(':') {
+            Some(i) => plugins.iter()
+                .find(|p| p.name() == &name[..i])
+                .and_then(|p| command_of(*p, &name[i + 1..])),
+            None => plugins.iter()
+                .find(|p| p.name() == name)
+                .map(|p| (p.name(), p.name().to_string(), p.access()))
+                .or_else(|| {
+                    plugins.iter().filter_map(|p| command_of(*p, name)).next()
+                })
+        };
+        match found {
+            None => {
+                conn.reply(msg, &format!("There is no plugin or command \
+                                          called `{}`", name))?;
+                Ok(None)
+            }
+            Some(("access", _, _)) | Some((_, _, Access::BotOwner)) => {
+                conn.reply(msg, &format!("The access of `{}` can't be changed",
+                                         name))?;
+                Ok(None)
+            }
+            Some((_, key, _)) => Ok(Some(key))
+        }
+    }
+
+    fn update(&self, msg: &Message, conn: &Connection, name: &str,
+              access: Option<Access>) -> Result<()> {
+        let server = msg.server_id().unwrap();
+        if let Err(err) = self.rules.set(server, name, access) {
+            println!("[Error] Failed to save {}: {}", FILE_PATH, err);
+        }
+        let text = match self.rules.get(server, name) {
+            Some(access) => format!("`{}` can now be used by {}", name, access),
+            None => format!("`{}` is back to its default access", name)
+        };
+        conn.reply(msg, &text)?;
+        Ok(())
+    }
+}
+
+impl Plugin for AccessPlugin {
+    /// Creates an `AccessPlugin` with rules of its own that knows of no
+    /// plugins. Use the one the `Bot` registers instead.
+    fn new() -> Box<Plugin> {
+        Box::new(AccessPlugin::with_rules(Arc::new(AccessRules::load()),
+                                          Weak::new()))
+    }
+
+    fn name(&self) -> &'static str {
+        "access"
+    }
+
+    fn description(&self) -> &'static str {
+        "Changes who may use each command"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    fn is_match(&self, msg: &Message) -> bool {
+        self.commands.is_match(msg)
+    }
+
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+    use discord::model::{ChannelId, ServerId};
+    use discord::model::permissions::{ADMINISTRATOR, MANAGE_MESSAGES};
+    use bot::{Connection, Message};
+    use command::Command;
+    use error::Result;
+    use executor::PluginExecutor;
+    use plugin::Plugin;
+    use store;
+    use transport::{fake_message, fake_role, fake_user, test_connection};
+    use super::{Access, AccessPlugin, AccessRules};
+
+    /// Has a `meme` command anyone may use and a `memeinfo` one for
+    /// moderators, like the meme plugin.
+    struct Memes;
+
+    impl Plugin for Memes {
+        fn new() -> Box<Plugin> {
+            Box::new(Memes)
+        }
+
+        fn name(&self) -> &'static str {
+            "meme"
+        }
+
+        fn commands(&self) -> Vec<Command> {
+            vec![Command::new("meme"),
+                 Command::new("memeinfo")
+                 .access(Access::Permission(MANAGE_MESSAGES)),
+                 Command::new("purge").access(Access::BotOwner)]
+        }
+
+        fn is_match(&self, _msg: &Message) -> bool {
+            false
+        }
+
+        fn handle(&self, _msg: &Message, _conn: &Connection) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn message(content: &str) -> Message {
+        let msg = fake_message(ChannelId(1), fake_user(1, "user"), content);
+        Message::with_server(msg, Some(ServerId(5)))
+    }
+
+    fn command(name: &str) -> Command {
+        Memes.commands().into_iter().find(|c| c.name == name).unwrap()
+    }
+
+    #[test]
+    fn parses_every_level() {
+        assert_eq!(Access::parse("everyone"), Ok(Access::Everyone));
+        assert_eq!(Access::parse(" Server-Owner "), Ok(Access::ServerOwner));
+        assert_eq!(Access::parse("bot-owner"), Ok(Access::BotOwner));
+        assert_eq!(Access::parse("roles Mod, @DJ"),
+                   Ok(Access::Roles(vec!["Mod".to_string(),
+                                         "DJ".to_string()])));
+        assert_eq!(Access::parse("permission Manage Messages"),
+                   Ok(Access::Permission(MANAGE_MESSAGES)));
+
+        assert_eq!(Access::parse("roles ,"), Err("No roles given".to_string()));
+        assert!(Access::parse("permission fly").unwrap_err()
+                .starts_with("There is no `fly` permission"));
+        assert!(Access::parse("everyone else").unwrap_err()
+                .starts_with("Expected"));
+        assert!(Access::parse("roles").is_err());
+
+        for spec in &["everyone", "roles Mod, DJ", "permission manage_server",
+                      "server-owner", "bot-owner"] {
+            assert_eq!(Access::parse(spec).unwrap().spec(), *spec);
+        }
+    }
+
+    #[test]
+    fn lets_in_who_the_level_names() {
+        let plain = message("!meme");
+        let mut modded = message("!meme");
+        modded.set_roles(vec![fake_role(1, "Mod")]);
+        modded.set_permissions(Some(MANAGE_MESSAGES));
+        let mut admin = message("!meme");
+        admin.set_permissions(Some(ADMINISTRATOR));
+        let mut server_owner = message("!meme");
+        server_owner.set_server_owner(true);
+        let mut bot_owner = message("!meme");
+        bot_owner.set_owner(true);
+
+        let mods = Access::Roles(vec!["mod".to_string()]);
+        let managers = Access::Permission(MANAGE_MESSAGES);
+        assert!(Access::Everyone.allows(&plain));
+        assert!(!mods.allows(&plain) && mods.allows(&modded));
+        assert!(!managers.allows(&plain) && managers.allows(&modded));
+        assert!(managers.allows(&admin) && !mods.allows(&admin));
+        assert!(!Access::ServerOwner.allows(&modded));
+        assert!(Access::ServerOwner.allows(&server_owner));
+        assert!(mods.allows(&server_owner));
+
+        assert!(!Access::BotOwner.allows(&server_owner));
+        for access in &[mods, managers, Access::ServerOwner, Access::BotOwner] {
+            assert!(access.allows(&bot_owner));
+        }
+    }
+
+    #[test]
+    fn command_overrides_win_over_plugin_overrides() {
+        let path = store::test_path("access-rules.json");
+        let rules = AccessRules::load_from(path);
+        let server = Some(ServerId(5));
+        let (meme, memeinfo) = (command("meme"), command("memeinfo"));
+        assert_eq!(rules.required(server, &Memes, Some(&meme)),
+                   Access::Everyone);
+        assert_eq!(rules.required(server, &Memes, Some(&memeinfo)),
+                   Access::Permission(MANAGE_MESSAGES));
+
+        let djs = Access::Roles(vec!["DJ".to_string()]);
+        rules.set(ServerId(5), "meme", Some(djs.clone())).unwrap();
+        assert_eq!(rules.required(server, &Memes, Some(&meme)), djs);
+        assert_eq!(rules.required(server, &Memes, Some(&memeinfo)), djs);
+
+        rules.set(ServerId(5), "meme:memeinfo", Some(Access::ServerOwner))
+            .unwrap();
+        assert_eq!(rules.required(server, &Memes, Some(&meme)), djs);
+        assert_eq!(rules.required(server, &Memes, Some(&memeinfo)),
+                   Access::ServerOwner);
+
+        // Other servers and private messages keep the declared levels.
+        assert_eq!(rules.required(Some(ServerId(6)), &Memes, Some(&meme)),
+                   Access::Everyone);
+        assert_eq!(rules.required(None, &Memes, Some(&memeinfo)),
+                   Access::Permission(MANAGE_MESSAGES));
+    }
+
+    #[test]
+    fn bot_owner_commands_cannot_be_overridden() {
+        let path = store::test_path("access-owner.json");
+        let rules = AccessRules::load_from(path);
+        rules.set(ServerId(5), "meme", Some(Access::Everyone)).unwrap();
+        rules.set(ServerId(5), "meme:purge", Some(Access::Everyone)).unwrap();
+        assert_eq!(rules.required(Some(ServerId(5)), &Memes,
+                                  Some(&command("purge"))),
+                   Access::BotOwner);
+    }
+
+    #[test]
+    fn access_names_a_plugin_or_one_of_its_commands() {
+        let path = store::test_path("access-plugin.json");
+        let rules = Arc::new(AccessRules::load_from(path.clone()));
+        let registry = Arc::new(RwLock::new(Vec::new()));
+        let plugin = AccessPlugin::with_rules(rules.clone(),
+                                              Arc::downgrade(&registry));
+        registry.write().unwrap().push(PluginExecutor::new(Memes::new()));
+        registry.write().unwrap()
+            .push(PluginExecutor::new(AccessPlugin::new()));
+        let (fake, conn) = test_connection();
+        for text in &["!access meme roles DJ",
+                      "!access memeinfo everyone",
+                      "!access meme:meme server-owner",
+                      "!access purge everyone",
+                      "!access access everyone",
+                      "!access nope everyone"] {
+            plugin.handle(&message(text), &conn).unwrap();
+        }
+
+        let sent = fake.sent().into_iter().map(|(_, text)| text)
+            .collect::<Vec<String>>();
+        assert_eq!(sent, vec![
+            "<@1> `meme` can now be used by members with the DJ role",
+            "<@1> `meme:memeinfo` can now be used by everyone",
+            "<@1> `meme:meme` can now be used by the server owner",
+            "<@1> The access of `purge` can't be changed",
+            "<@1> The access of `access` can't be changed",
+            "<@1> There is no plugin or command called `nope`"]);
+
+        // The overrides were saved under those names.
+        let saved = AccessRules::load_from(path);
+        assert_eq!(saved.get(ServerId(5), "meme:memeinfo"),
+                   Some(Access::Everyone));
+        assert_eq!(saved.get(ServerId(5), "memeinfo"), None);
+        assert!(saved.get(ServerId(5), "meme").is_some());
+    }
+}
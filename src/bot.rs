@@ -1,171 +1,1124 @@
+use std::cmp;
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::thread;
-use discord::{Discord, Connection as DiscordConnection, ChannelRef,
-             State, Error};
+use std::time::{Duration, Instant};
+use rand::{self, Rng};
+use signal_hook;
+use discord::{Discord, ChannelRef, State, Error};
+use discord::Error as DiscordError;
 use discord::model::{Message as DiscordMessage, MessageType, Event,
                      ChannelType, MessageId, ChannelId, RoleId, Attachment,
-                     MessageReaction, User, ReadyEvent};
-use plugin::Plugin;
+                     MessageReaction, User, UserId, ReadyEvent, ServerId,
+                     ReactionEmoji, Permissions, LiveServer, PossibleServer,
+                     Role};
+use plugin::{Events, Plugin};
+use transport::{Transport, DiscordTransport, EventStream};
+use pool::{WorkerPool, PoolStats};
+use executor::{self, FullPolicy, PluginExecutor, QueueStats, Task};
+use error::{self, Error as BotError};
+use split::split_message;
+use outbox::Outbox;
+use embed::Embed;
+use help::HelpPlugin;
+use config::{self, Config, Logging, Runtime, Source};
+use prefix::{Prefixes, PrefixPlugin, DEFAULT_PREFIX};
+use reload::{Build, Reload, ReloadPlugin};
+use access::{AccessRules, AccessPlugin};
+use toggle::{Toggles, TogglePlugin};
+use limit::{self, Verdict};
+use middleware::{Log, Middleware, Pipeline};
+use command::{self, Command};
+
+/// The delay before the first reconnect attempt, doubled on every further
+/// consecutive failure.
+const INITIAL_BACKOFF_MS: u64 = 1000;
+/// The upper bound on the delay between reconnect attempts.
+const MAX_BACKOFF_MS: u64 = 5 * 60 * 1000;
+/// How long a session must stay up before its reconnect attempts stop
+/// counting towards the retry limit.
+const STABLE_SESSION_SECS: u64 = 60;
+/// The number of threads running plugin handlers unless `set_pool` is used.
+const DEFAULT_WORKERS: usize = 4;
+/// The number of matched messages that may wait in each plugin's queue.
+const DEFAULT_QUEUE_CAPACITY: usize = 32;
+/// The number of gateway events that may wait in each plugin's queue, on top
+/// of the messages.
+const EVENT_QUEUE_CAPACITY: usize = 32;
+/// How long a handler may run before it is reported as slow.
+const DEFAULT_HANDLER_TIMEOUT_SECS: u64 = 30;
+/// How long a shutdown waits for queued handlers.
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+/// How long a shutdown waits for the gateway connection to close.
+const GATEWAY_CLOSE_TIMEOUT_SECS: u64 = 5;
+/// How often the event loop checks whether it has been asked to stop.
+const POLL_INTERVAL_MS: u64 = 250;
+/// The most characters Discord accepts in one message.
+pub const MAX_MESSAGE_LEN: usize = 2000;
+/// The largest file in bytes Discord accepts as an upload.
+pub const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+/// How many times a message sent directly is tried when rate limited.
+const MAX_SEND_ATTEMPTS: u32 = 3;
+/// How long a shutdown waits for queued outgoing messages to be sent.
+const OUTBOX_FLUSH_TIMEOUT_SECS: u64 = 5;
 
 pub struct Bot {
     conn: Connection,
-    // FIXME is this really necessary?
-    // Don't think each plugin needs its own Arc.
-    // TODO busstop instead of std mutex
-    plugins: Arc<Mutex<Vec<Arc<Mutex<Box<Plugin>>>>>>
+    plugins: Arc<RwLock<Vec<PluginExecutor>>>,
+    prefixes: Arc<Prefixes>,
+    access: Arc<AccessRules>,
+    toggles: Arc<Toggles>,
+    pipeline: Arc<Pipeline>,
+    logging: Arc<RwLock<Logging>>,
+    owners: Arc<RwLock<Vec<UserId>>>,
+    reload: Option<Arc<Reload>>,
+    pool: Arc<WorkerPool>,
+    queue_capacity: usize,
+    policy: FullPolicy,
+    handler_timeout: Arc<Mutex<Duration>>,
+    shutdown_timeout: Duration,
+    stopping: Arc<AtomicBool>,
+    max_retries: Option<u32>
 }
 
 impl Bot {
-    /// Creates a new `Bot`.
+    /// Creates a new `Bot` logged in with the token in `DISCORD_TOKEN`.
     pub fn new() -> Self {
-        let discord = Discord::from_bot_token(
-            &env::var("DISCORD_TOKEN").expect("Expected token"),
-        ).expect("Login failed");
+        Bot::with_token(&env::var("DISCORD_TOKEN").expect("Expected token"))
+    }
+
+    /// Creates a new `Bot` logged in with the given token.
+    pub fn with_token(token: &str) -> Self {
+        let discord = Discord::from_bot_token(token).expect("Login failed");
+        Bot::with_transport(Arc::new(DiscordTransport::new(discord)))
+    }
 
-        Bot {
-            conn: Connection::new(discord),
-            plugins: Arc::new(Mutex::new(Vec::new()))
+    /// Creates a new `Bot` that talks to the given `Transport`.
+    pub fn with_transport(transport: Arc<Transport>) -> Self {
+        let plugins = Arc::new(RwLock::new(Vec::new()));
+        let prefixes = Arc::new(Prefixes::load());
+        let access = Arc::new(AccessRules::load());
+        let toggles = Arc::new(Toggles::load());
+        let help = HelpPlugin::with_plugins(Arc::downgrade(&plugins),
+                                            access.clone(), toggles.clone());
+        let prefix = PrefixPlugin::with_prefixes(prefixes.clone());
+        let access_plugin = AccessPlugin::with_rules(access.clone(),
+                                                     Arc::downgrade(&plugins));
+        let toggle = TogglePlugin::with_toggles(toggles.clone(),
+                                                Arc::downgrade(&plugins));
+        {
+            let mut registry = plugins.write().unwrap();
+            let builtins: Vec<Box<Plugin>> = vec![Box::new(help),
+                                                  Box::new(prefix),
+                                                  Box::new(access_plugin),
+                                                  Box::new(toggle)];
+            for plugin in builtins {
+                executor::insert(&mut registry, PluginExecutor::new(plugin));
+            }
         }
+        let handler_timeout = Arc::new(Mutex::new(
+            Duration::from_secs(DEFAULT_HANDLER_TIMEOUT_SECS)));
+        spawn_watchdog(Arc::downgrade(&plugins), handler_timeout.clone());
+
+        let mut bot = Bot {
+            conn: Connection::new(transport),
+            plugins: plugins,
+            prefixes: prefixes,
+            access: access,
+            toggles: toggles,
+            pipeline: Arc::new(Pipeline::new()),
+            logging: Arc::new(RwLock::new(Logging::default())),
+            owners: Arc::new(RwLock::new(Vec::new())),
+            reload: None,
+            pool: Arc::new(WorkerPool::new(DEFAULT_WORKERS)),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            policy: FullPolicy::Busy,
+            handler_timeout: handler_timeout,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            stopping: Arc::new(AtomicBool::new(false)),
+            max_retries: None
+        };
+        bot.add_middleware(Box::new(Log));
+        bot
     }
 
-    pub fn connect(&mut self) {
-        let (mut connection, ready) = self.conn.connect()
-            .expect("Connect failed");
-        let mut state = State::new(ready);
-        let channel_count: usize = state.servers().iter()
-            .map(|srv| srv.channels.iter()
-                 .filter(|chan| chan.kind == ChannelType::Text)
-                 .count()
-                 ).fold(0, |v, s| v + s);
-        println!("[Ready] {} logging {} servers with {} text channels",
-                 state.user().username, state.servers().len(), channel_count);
+    /// Replaces the worker pool that runs plugin handlers. `workers` threads
+    /// are shared by all plugins. Each plugin queues at most `capacity`
+    /// matched messages, and `policy` decides what happens to a matched
+    /// message when that queue is full. Dispatching never waits for room.
+    pub fn set_pool(&mut self, workers: usize, capacity: usize,
+                    policy: FullPolicy) {
+        self.pool = Arc::new(WorkerPool::new(workers));
+        self.queue_capacity = capacity;
+        self.policy = policy;
+    }
 
-        let plugins = self.plugins.clone();
+    /// Sets what is logged besides warnings and errors.
+    pub fn set_logging(&mut self, logging: Logging) {
+        *self.logging.write().unwrap() = logging;
+    }
 
-        loop {
-            let event = match connection.recv_event() {
-                Ok(event) => event,
-                Err(Error::Closed(code, body)) => {
-                    println!("[Error] Connection closed with status {:?}: {}",
-                             code, body);
-                    break
-                }
+    /// Applies `config`, registering the plugins it enables with `build`,
+    /// and registers the `!reload` command. From then on the config is read
+    /// from `source` again on `reload`, `!reload` or SIGHUP, which is handled
+    /// whether or not the bot is connected. The `[runtime]` settings are only
+    /// read here.
+    pub fn configure(&mut self, source: Source, config: Config, build: Build)
+        -> config::Result<()> {
+        self.apply_runtime(&config.runtime);
+        let reload = Arc::new(Reload::new(source, build,
+                                          Arc::downgrade(&self.plugins),
+                                          self.prefixes.clone(),
+                                          self.logging.clone(),
+                                          self.owners.clone(),
+                                          self.conn.clone()));
+        reload.apply(config)?;
+        let plugin = ReloadPlugin::with_reload(Arc::downgrade(&reload));
+        self.register(Box::new(plugin));
+        spawn_reloader(Arc::downgrade(&reload));
+        self.reload = Some(reload);
+        Ok(())
+    }
+
+    /// Applies the `[runtime]` settings the config sets, leaving the others
+    /// as they are.
+    fn apply_runtime(&mut self, runtime: &Runtime) {
+        self.set_max_retries(runtime.max_retries);
+        if runtime.workers.is_some() || runtime.queue_capacity.is_some() ||
+            runtime.when_full.is_some() {
+            self.set_pool(runtime.workers.unwrap_or(DEFAULT_WORKERS),
+                          runtime.queue_capacity
+                          .unwrap_or(DEFAULT_QUEUE_CAPACITY),
+                          runtime.when_full.unwrap_or(FullPolicy::Busy));
+        }
+        if let Some(timeout) = runtime.handler_timeout {
+            self.set_handler_timeout(timeout);
+        }
+        if let Some(timeout) = runtime.shutdown_timeout {
+            self.set_shutdown_timeout(timeout);
+        }
+    }
+
+    /// Reads the config again and applies it, as SIGHUP does. A config that
+    /// is invalid or fails to apply leaves everything as it was.
+    pub fn reload(&self) {
+        let reload = match self.reload {
+            Some(ref reload) => reload,
+            None => {
+                println!("[Warning] There is no config to reload");
+                return
+            }
+        };
+        match reload.reload() {
+            Ok(changes) => println!("[Reload] {}", changes),
+            Err(err) => println!("[Error] Config not reloaded: {}", err)
+        }
+    }
+
+    /// Adds a middleware around every plugin's `handle`. Middleware runs in
+    /// the order it was added, after the `Log` every `Bot` starts with.
+    pub fn add_middleware(&mut self, middleware: Box<Middleware>) {
+        self.pipeline.push(middleware);
+    }
+
+    /// Sets how long a handler may run before it is reported as slow.
+    pub fn set_handler_timeout(&mut self, timeout: Duration) {
+        *self.handler_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Returns how saturated the worker pool is.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Returns the state of every plugin's message queue.
+    pub fn queue_stats(&self) -> Vec<(&'static str, QueueStats)> {
+        self.plugins.read().unwrap().iter()
+            .map(|e| (e.plugin().name(), e.stats()))
+            .collect()
+    }
+
+    /// Blocks until every dispatched handler has finished.
+    pub fn wait_idle(&self) {
+        self.pool.wait_idle();
+    }
+
+    /// Sets how many consecutive reconnect attempts are made before
+    /// `connect` gives up and returns. `None` retries forever. Attempts are
+    /// only counted afresh once a session has stayed up for
+    /// `STABLE_SESSION_SECS`.
+    pub fn set_max_retries(&mut self, retries: Option<u32>) {
+        self.max_retries = retries;
+    }
+
+    /// Sets how long `shutdown` waits for queued handlers before giving up
+    /// on them.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Asks a running `connect` to shut down, as SIGINT and SIGTERM do.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Connects to the gateway and dispatches events to the registered
+    /// plugins until the bot is stopped or the retry limit is exhausted.
+    ///
+    /// Transient errors are resumed by the gateway connection itself, which
+    /// surfaces as `Event::Resumed`. Once it reports the connection as
+    /// closed, a fresh session is started and `State` is rebuilt from its
+    /// `ReadyEvent`. Registered plugins are kept across reconnects.
+    ///
+    /// Plugins are told about every new session through `Plugin::on_ready`,
+    /// ticked while the bot is running, and shut down before this returns.
+    /// SIGINT and SIGTERM stop the bot: no further events are dispatched,
+    /// queued handlers are given `set_shutdown_timeout` to finish and the
+    /// gateway connection is closed.
+    ///
+    /// Returns `true` if the bot was stopped and shut down cleanly, and
+    /// `false` if it gave up reconnecting or handlers did not finish in time.
+    pub fn connect(&mut self) -> bool {
+        for &signal in &[signal_hook::SIGINT, signal_hook::SIGTERM] {
+            if let Err(err) = signal_hook::flag::register(signal,
+                                                          self.stopping.clone()) {
+                println!("[Warning] Failed to handle signal {}: {}", signal, err);
+            }
+        }
+
+        let ticking = Arc::new(AtomicBool::new(true));
+        self.spawn_ticker(ticking.clone());
+        let mut retries = Retries::new(self.max_retries);
+        let mut open_session = None;
+
+        'sessions: while !self.stopping.load(Ordering::SeqCst) {
+            let (stream, ready) = match self.conn.connect() {
+                Ok(c) => c,
                 Err(err) => {
-                    println!("[Warning] Receive error: {:?}", err);
+                    println!("[Error] Connect failed: {:?}", err);
+                    if !self.backoff(&mut retries) { break 'sessions }
                     continue
                 }
             };
-            state.update(&event);
-
-            match event {
-                Event::MessageCreate(message) => {
-                    match state.find_channel(message.channel_id) {
-                        Some(ChannelRef::Public(server, channel)) => {
-                            println!("[{} #{}] {}: {}",
-                                     server.name,
-                                     channel.name,
-                                     message.author.name,
-                                     message.content);
-                        }
-                        Some(ChannelRef::Group(group)) => {
-                            println!("[Group {}] {}: {}",
-                                     group.name(),
-                                     message.author.name,
-                                     message.content);
+            let connected_at = Instant::now();
+            let mut state = State::new(ready);
+            log_ready(&state);
+            self.prefixes.set_user(state.user().id);
+            self.ready(&state);
+            let session = Session::spawn(stream, self.stopping.clone());
+
+            loop {
+                if self.stopping.load(Ordering::SeqCst) {
+                    open_session = Some(session);
+                    break 'sessions
+                }
+
+                let event = match session.events.recv_timeout(
+                    Duration::from_millis(POLL_INTERVAL_MS)) {
+                    Ok(Ok(event)) => event,
+                    Ok(Err(Error::Closed(code, body))) => {
+                        println!("[Error] Connection closed with status {:?}: {}",
+                                 code, body);
+                        break
+                    }
+                    Ok(Err(err)) => {
+                        println!("[Warning] Receive error: {:?}", err);
+                        continue
+                    }
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        println!("[Error] Gateway reader stopped");
+                        break
+                    }
+                };
+                state.update(&event);
+
+                match event {
+                    Event::Ready(ready) => {
+                        state = State::new(ready);
+                        log_ready(&state);
+                        self.ready(&state);
+                    }
+                    Event::Resumed { .. } => {
+                        println!("[Resumed] Session resumed");
+                    }
+                    Event::MessageCreate(message) => {
+                        if self.logging.read().unwrap().messages {
+                            log_message(&state, &message);
                         }
-                        Some(ChannelRef::Private(channel)) => {
-                            if message.author.name == channel.recipient.name {
-                                println!("[Private] {}: {}",
-                                         message.author.name,
-                                         message.content);
-                            } else {
-                                println!("[Private] To {}: {}",
-                                         channel.recipient.name,
-                                         message.content);
+                        let msg = match state.find_channel(message.channel_id) {
+                            Some(ChannelRef::Public(server, channel)) => {
+                                let author = message.author.id;
+                                let mut msg = Message::with_server(
+                                    message, Some(server.id));
+                                msg.set_permissions(Some(
+                                    server.permissions_for(channel.id, author)));
+                                msg.set_roles(author_roles(server, author));
+                                msg.set_server_owner(server.owner_id == author);
+                                msg
                             }
+                            _ => Message::new(message)
+                        };
+                        self.dispatch(msg);
+                    }
+                    Event::Unknown(name, data) => {
+                        if self.logging.read().unwrap().events {
+                            println!("[Unknown Event] {}: {:?}", name, data);
                         }
-                        None => println!("[Unknown Channel] {}: {}",
-                                         message.author.name,
-                                         message.content),
-                    };
-
-                    let msg = Message::new(message);
-                    for p in plugins.lock().unwrap().iter()
-                        .filter(|&p| p.lock().unwrap().is_match(&msg)) {
-                            // FIXME: Don't actually want to clone the plugin
-                            // every time it is matched. This is bad because
-                            // a plugin could possibly contain a data structure
-                            // acting as a database - it will be slow.
-                            //
-                            // I don't think there is an alternative.
-                            let p_1 = p.clone();
-                            let conn_1 = self.conn.clone();
-                            let msg_1 = msg.clone();
-                            println!("[plugin] Spawning thread");
-                            thread::spawn(move || {
-                                p_1.lock().unwrap().handle(&msg_1, &conn_1);
-                            });
+                    }
+                    event => {
+                        if let Some(kind) = executor::event_kind(&event) {
+                            self.dispatch_event(&state, event, kind);
                         }
+                    }
                 }
-                Event::Unknown(name, data) => {
-                    println!("[Unknown Event] {}: {:?}", name, data);
+            }
+
+            retries.session_ended(connected_at.elapsed());
+            if !self.backoff(&mut retries) { break 'sessions }
+        }
+
+        ticking.store(false, Ordering::SeqCst);
+        let stopped = self.stopping.load(Ordering::SeqCst);
+        if stopped {
+            println!("[Shutdown] Waiting for plugins to finish");
+        }
+        let finished = self.shutdown();
+
+        if let Some(session) = open_session {
+            println!("[Shutdown] Closing gateway connection");
+            let timeout = Duration::from_secs(GATEWAY_CLOSE_TIMEOUT_SECS);
+            if session.closed.recv_timeout(timeout).is_err() {
+                println!("[Warning] Gateway connection did not close in time, \
+                          it is dropped on exit");
+            }
+        }
+        stopped && finished
+    }
+
+    /// Waits up to the shutdown timeout for every queued handler to finish,
+    /// then calls `Plugin::on_shutdown` on each registered plugin and sends
+    /// any queued messages. Returns `false` if handlers were still running
+    /// when the hooks were called.
+    pub fn shutdown(&self) -> bool {
+        let finished = self.pool.wait_idle_timeout(self.shutdown_timeout);
+        if !finished {
+            println!("[Warning] Handlers still running after {}s: {:?}",
+                     self.shutdown_timeout.as_secs(), self.queue_stats());
+        }
+        self.log_stats();
+
+        for e in self.plugins.read().unwrap().iter() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                e.plugin().on_shutdown(&self.conn);
+            }));
+            if result.is_err() {
+                println!("[Error] {} panicked while shutting down",
+                         e.plugin().name());
+            }
+        }
+
+        let timeout = Duration::from_secs(OUTBOX_FLUSH_TIMEOUT_SECS);
+        if !self.conn.flush_queue(timeout) {
+            println!("[Warning] {} queued messages were not sent",
+                     self.conn.queue_depth());
+        }
+        finished
+    }
+
+    /// Logs how much work the plugins did, and which of them had to turn
+    /// messages away because their queue was full.
+    fn log_stats(&self) {
+        let pool = self.pool_stats();
+        println!("[Shutdown] {} tasks run on {} workers, {} still queued",
+                 pool.completed, pool.workers, pool.queued);
+        for (name, stats) in self.queue_stats() {
+            if stats.rejected > 0 {
+                println!("[Shutdown] {} handled {} tasks and dropped {}",
+                         name, stats.handled, stats.rejected);
+            }
+        }
+    }
+
+    /// Matches the message against the plugins that are on in its channel,
+    /// in order of priority, until one that queued it consumes it. Each match
+    /// is queued if its author may use the plugin, within the plugin's rate
+    /// limit.
+    pub fn dispatch(&self, mut msg: Message) {
+        self.prefixes.apply(&mut msg);
+        if self.owners.read().unwrap().contains(&msg.author().id) {
+            msg.set_owner(true);
+        }
+        for e in self.plugins.read().unwrap().iter()
+            .filter(|e| self.toggles.is_enabled(&msg, e.plugin().name()))
+            .filter(|e| e.plugin().is_match(&msg)) {
+                if self.offer(e, &msg) && e.plugin().consumes(&msg) { break }
+            }
+    }
+
+    /// Queues a matched message with the plugin, unless the author may not
+    /// use it or must slow down, in which case they are told. This runs on
+    /// the gateway thread, so those replies are queued rather than sent and
+    /// waited on. Returns whether the message was queued.
+    fn offer(&self, e: &PluginExecutor, msg: &Message) -> bool {
+        let command = command::resolve(e.plugin().commands(), msg);
+        if !self.check_access(e.plugin(), command.as_ref(), msg) {
+            return false
+        }
+        if !self.check_rate_limit(e, command.as_ref(), msg) { return false }
+        let task = Task::Message(msg.clone(), self.pipeline.clone());
+        let queued = e.enqueue(task, &self.conn, &self.pool,
+                               self.queue_capacity);
+
+        if !queued {
+            println!("[Warning] {} queue full, dropping message: {:?}",
+                     e.plugin().name(), e.stats());
+            if self.policy == FullPolicy::Busy {
+                self.conn.queue_reply(msg, "I'm busy right now, try again \
+                                            in a bit");
+            }
+        }
+        queued
+    }
+
+    /// Returns whether the author of `msg` may use the plugin, or the
+    /// command of it the message calls, and refuses politely if not. Both
+    /// the access rules and `Plugin::can_use` must allow a command.
+    fn check_access(&self, plugin: &Plugin, command: Option<&Command>,
+                    msg: &Message) -> bool {
+        let required = self.access.required(msg.server_id(), plugin, command);
+        let what = match command {
+            Some(c) => format!("`{}{}`", msg.prefix(), c.name),
+            None => plugin.name().to_string()
+        };
+        if !required.allows(msg) {
+            self.conn.queue_reply(msg, &format!("Sorry, only {} can use {}",
+                                                required, what));
+            return false
+        }
+        if !command.map_or(true, |c| plugin.can_use(c, msg)) {
+            self.conn.queue_reply(msg, &format!("Sorry, you can't use {}",
+                                                what));
+            return false
+        }
+        true
+    }
+
+    /// Returns whether the message is within the rate limit of the plugin's
+    /// command it calls, and tells the author to slow down the first time it
+    /// is not.
+    fn check_rate_limit(&self, e: &PluginExecutor, command: Option<&Command>,
+                        msg: &Message) -> bool {
+        let limit = match e.plugin().rate_limit(command) {
+            Some(limit) => limit,
+            None => return true
+        };
+        match e.limiter().check(&limit, command.map(|c| c.name), msg) {
+            Verdict::Allow => true,
+            Verdict::Deny { retry_after, warn } => {
+                if warn {
+                    self.conn.queue_reply(msg, &limit::slow_down(retry_after));
                 }
-                _ => {}
+                false
+            }
+        }
+    }
+
+    /// Queues a non-message event with every plugin that asked for its kind
+    /// and is turned on where it happened. Events are dropped rather than
+    /// waited on when a plugin already has `EVENT_QUEUE_CAPACITY` of them
+    /// queued.
+    fn dispatch_event(&self, state: &State, event: Event, kind: Events) {
+        let (server, channel) = event_place(state, &event);
+        let event = Arc::new(event);
+        for e in self.plugins.read().unwrap().iter()
+            .filter(|e| e.wants(kind))
+            .filter(|e| {
+                self.toggles.is_enabled_in(server, channel, e.plugin().name())
+            }) {
+            let queued = e.enqueue(Task::Event(event.clone()), &self.conn,
+                                   &self.pool, EVENT_QUEUE_CAPACITY);
+            if !queued {
+                println!("[Warning] {} queue full, dropping event",
+                         e.plugin().name());
             }
         }
     }
 
+    /// Adds a plugin, after those of the same or a higher priority.
     pub fn register(&mut self, plugin: Box<Plugin>) {
-        self.plugins.lock().unwrap().push(Arc::new(Mutex::new(plugin)));
+        executor::insert(&mut self.plugins.write().unwrap(),
+                         PluginExecutor::new(plugin));
+    }
+
+    /// Calls `Plugin::on_ready` on every registered plugin.
+    fn ready(&self, state: &State) {
+        for e in self.plugins.read().unwrap().iter() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                e.plugin().on_ready(state, &self.conn);
+            }));
+            if result.is_err() {
+                println!("[Error] {} panicked in on_ready", e.plugin().name());
+            }
+        }
+    }
+
+    /// Queues `Plugin::on_tick` for every plugin whose tick is due, checking
+    /// once a second until `ticking` is cleared.
+    fn spawn_ticker(&self, ticking: Arc<AtomicBool>) {
+        let plugins = self.plugins.clone();
+        let conn = self.conn.clone();
+        let pool = self.pool.clone();
+        let capacity = self.queue_capacity;
+
+        thread::spawn(move || {
+            while ticking.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_secs(1));
+                for e in plugins.read().unwrap().iter()
+                    .filter(|e| e.tick_due()) {
+                        if !e.enqueue(Task::Tick, &conn, &pool, capacity) {
+                            println!("[Warning] {} queue full, skipping tick",
+                                     e.plugin().name());
+                        }
+                    }
+            }
+        });
+    }
+
+    /// Sleeps before the next reconnect attempt using exponential backoff with
+    /// jitter. Returns `false` once the retry limit has been reached.
+    fn backoff(&self, retries: &mut Retries) -> bool {
+        let delay = match retries.next_delay() {
+            Some(delay) => delay,
+            None => {
+                println!("[Error] Giving up after {} reconnect attempts",
+                         retries.attempt - 1);
+                return false
+            }
+        };
+        let jitter = rand::thread_rng().gen_range(0, delay / 2 + 1);
+        println!("[Reconnect] Attempt {} in {}ms", retries.attempt,
+                 delay + jitter);
+
+        // Sleep in slices so that a stop request is not held up.
+        let deadline = Instant::now() + Duration::from_millis(delay + jitter);
+        while Instant::now() < deadline {
+            if self.stopping.load(Ordering::SeqCst) { return false }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        !self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts consecutive reconnect attempts. A session that drops soon after
+/// connecting does not reset the count, so that a gateway which keeps
+/// accepting and then closing the connection still reaches the limit.
+struct Retries {
+    attempt: u32,
+    max: Option<u32>
+}
+
+impl Retries {
+    fn new(max: Option<u32>) -> Retries {
+        Retries {
+            attempt: 0,
+            max: max
+        }
+    }
+
+    /// Counts another attempt, and returns how many milliseconds to wait
+    /// before making it, or `None` if it would be over the limit.
+    fn next_delay(&mut self) -> Option<u64> {
+        self.attempt += 1;
+        if self.max.map_or(false, |max| self.attempt > max) {
+            return None
+        }
+        // Cap the shift so the delay saturates instead of overflowing.
+        Some(cmp::min(INITIAL_BACKOFF_MS << cmp::min(self.attempt - 1, 16),
+                      MAX_BACKOFF_MS))
+    }
+
+    /// Starts counting again if the session that just ended stayed up for
+    /// at least `STABLE_SESSION_SECS`.
+    fn session_ended(&mut self, uptime: Duration) {
+        if uptime >= Duration::from_secs(STABLE_SESSION_SECS) {
+            self.attempt = 0;
+        }
+    }
+}
+
+/// A gateway session whose events are read on a separate thread, so that the
+/// event loop can notice a stop request without waiting for the next event.
+///
+/// The reader itself can't be interrupted: `EventStream::recv_event` blocks
+/// until the gateway sends something, and the stop request is only seen
+/// after that. On a quiet gateway the connection is then not closed cleanly
+/// within `GATEWAY_CLOSE_TIMEOUT_SECS`, and is dropped when the process exits
+/// instead.
+struct Session {
+    events: Receiver<Result<Event, Error>>,
+    // Receives once the connection has been shut down after a stop request.
+    closed: Receiver<()>
+}
+
+impl Session {
+    fn spawn(mut stream: Box<EventStream>, stopping: Arc<AtomicBool>)
+        -> Session {
+        let (events_tx, events_rx) = mpsc::channel();
+        let (closed_tx, closed_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            while !stopping.load(Ordering::SeqCst) {
+                let result = stream.recv_event();
+                let closed = match result {
+                    Err(Error::Closed(..)) => true,
+                    _ => false
+                };
+                if events_tx.send(result).is_err() || closed { return }
+            }
+
+            if let Err(err) = stream.shutdown() {
+                println!("[Warning] Failed to close gateway connection: {:?}",
+                         err);
+            }
+            let _ = closed_tx.send(());
+        });
+
+        Session {
+            events: events_rx,
+            closed: closed_rx
+        }
+    }
+}
+
+/// Reloads the config whenever SIGHUP is received, checking every
+/// `POLL_INTERVAL_MS`. Stops once the `Bot` owning the `Reload` is dropped.
+fn spawn_reloader(reload: Weak<Reload>) {
+    let requested = Arc::new(AtomicBool::new(false));
+    if let Err(err) = signal_hook::flag::register(signal_hook::SIGHUP,
+                                                  requested.clone()) {
+        println!("[Warning] Failed to handle signal {}: {}",
+                 signal_hook::SIGHUP, err);
+        return
+    }
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            let reload = match reload.upgrade() {
+                Some(reload) => reload,
+                None => return
+            };
+            if !requested.swap(false, Ordering::SeqCst) { continue }
+            match reload.reload() {
+                Ok(changes) => println!("[Reload] {}", changes),
+                Err(err) => println!("[Error] Config not reloaded: {}", err)
+            }
+        }
+    });
+}
+
+/// Periodically reports handlers that have been running for too long. Stops
+/// once the `Bot` owning the plugins is dropped.
+fn spawn_watchdog(plugins: Weak<RwLock<Vec<PluginExecutor>>>,
+                  timeout: Arc<Mutex<Duration>>) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let plugins = match plugins.upgrade() {
+                Some(p) => p,
+                None => return
+            };
+            let timeout = *timeout.lock().unwrap();
+            for e in plugins.read().unwrap().iter() {
+                e.check_timeout(timeout);
+            }
+        }
+    });
+}
+
+/// The server and channel an event happened in, where it has them.
+fn event_place(state: &State, event: &Event)
+    -> (Option<ServerId>, Option<ChannelId>) {
+    let channel = match *event {
+        Event::MessageUpdate { channel_id, .. } |
+        Event::MessageDelete { channel_id, .. } => channel_id,
+        Event::ReactionAdd(ref reaction) |
+        Event::ReactionRemove(ref reaction) => reaction.channel_id,
+        Event::ServerMemberAdd(server, _) => return (Some(server), None),
+        Event::ServerMemberRemove(server, _) => return (Some(server), None),
+        Event::PresenceUpdate { server_id, .. } => return (server_id, None),
+        Event::ServerCreate(PossibleServer::Online(ref server)) => {
+            return (Some(server.id), None)
+        }
+        _ => return (None, None)
+    };
+    let server = match state.find_channel(channel) {
+        Some(ChannelRef::Public(server, _)) => Some(server.id),
+        _ => None
+    };
+    (server, Some(channel))
+}
+
+/// The roles `user` has in `server`.
+fn author_roles(server: &LiveServer, user: UserId) -> Vec<Role> {
+    let member = match server.members.iter().find(|m| m.user.id == user) {
+        Some(member) => member,
+        None => return Vec::new()
+    };
+    server.roles.iter()
+        .filter(|r| member.roles.contains(&r.id))
+        .cloned()
+        .collect()
+}
+
+fn log_message(state: &State, message: &DiscordMessage) {
+    match state.find_channel(message.channel_id) {
+        Some(ChannelRef::Public(server, channel)) => {
+            println!("[{} #{}] {}: {}",
+                     server.name,
+                     channel.name,
+                     message.author.name,
+                     message.content);
+        }
+        Some(ChannelRef::Group(group)) => {
+            println!("[Group {}] {}: {}",
+                     group.name(),
+                     message.author.name,
+                     message.content);
+        }
+        Some(ChannelRef::Private(channel)) => {
+            if message.author.name == channel.recipient.name {
+                println!("[Private] {}: {}",
+                         message.author.name,
+                         message.content);
+            } else {
+                println!("[Private] To {}: {}",
+                         channel.recipient.name,
+                         message.content);
+            }
+        }
+        None => {
+            println!("[Unknown Channel] {}: {}",
+                     message.author.name,
+                     message.content);
+        }
     }
 }
 
+fn log_ready(state: &State) {
+    let channel_count: usize = state.servers().iter()
+        .map(|srv| srv.channels.iter()
+             .filter(|chan| chan.kind == ChannelType::Text)
+             .count()
+             ).fold(0, |v, s| v + s);
+    println!("[Ready] {} logging {} servers with {} text channels",
+             state.user().username, state.servers().len(), channel_count);
+}
+
 #[derive(Clone)]
 pub struct Connection {
-    inner: Arc<Mutex<Discord>>
+    inner: Arc<Transport>,
+    outbox: Arc<Outbox>
 }
 
 impl Connection {
-    pub fn new(discord: Discord) -> Connection {
+    pub fn new(transport: Arc<Transport>) -> Connection {
         Connection {
-            inner: Arc::new(Mutex::new(discord))
+            outbox: Arc::new(Outbox::new(transport.clone(), MAX_MESSAGE_LEN)),
+            inner: transport
         }
     }
 
-    pub fn connect(&self) -> Result<(DiscordConnection, ReadyEvent), Error> {
-        self.inner.lock().unwrap().connect()
+    pub fn connect(&self) -> Result<(Box<EventStream>, ReadyEvent), Error> {
+        self.inner.connect()
     }
 
     /// Sends a message to the same channel in which the message was received.
-    pub fn send(&self, msg: &Message, text: &str) {
-        self.inner.lock().unwrap()
-            .send_message(msg.channel_id(), text, "", false)
-            .expect("Failed to send message");
+    /// Returns the id of the message sent.
+    pub fn send(&self, msg: &Message, text: &str) -> error::Result<MessageId> {
+        self.send_text(msg.channel_id(), text)
     }
 
     /// Sends a message to the same channel in which the message was received.
     /// Prefixes the message with a @mention of the user who sent the message.
-    pub fn reply(&self, msg: &Message, text: &str) {
-        self.inner.lock().unwrap()
-            .send_message(msg.channel_id(),
-            &format!("{} {}", msg.author().mention(), text), "", false)
-            .expect("Failed to send message");
+    /// Returns the id of the message sent.
+    pub fn reply(&self, msg: &Message, text: &str)
+        -> error::Result<MessageId> {
+        self.send_text(msg.channel_id(),
+                       &format!("{} {}", msg.author().mention(), text))
+    }
+
+    /// Like `send`, but text longer than Discord allows is split over several
+    /// messages. See `split::split_message` for how, and for `max_chunks`.
+    pub fn send_split(&self, msg: &Message, text: &str,
+                      max_chunks: Option<usize>) -> error::Result<()> {
+        for chunk in split_message(text, MAX_MESSAGE_LEN, max_chunks) {
+            self.send_text(msg.channel_id(), &chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Like `reply`, but text longer than Discord allows is split over
+    /// several messages. Only the first one @mentions the user.
+    pub fn reply_split(&self, msg: &Message, text: &str,
+                       max_chunks: Option<usize>) -> error::Result<()> {
+        let mention = format!("{} ", msg.author().mention());
+        let limit = MAX_MESSAGE_LEN - mention.chars().count();
+        for (i, chunk) in split_message(text, limit, max_chunks).iter()
+            .enumerate() {
+                if i == 0 {
+                    self.send_text(msg.channel_id(),
+                                   &format!("{}{}", mention, chunk))?;
+                } else {
+                    self.send_text(msg.channel_id(), chunk)?;
+                }
+            }
+        Ok(())
+    }
+
+    /// Queues a message to the same channel in which the message was
+    /// received and returns immediately. Queued messages are sent in order as
+    /// fast as the rate limits allow, and may be combined with other queued
+    /// messages to the same channel. Long text is split as by `send_split`.
+    pub fn queue(&self, msg: &Message, text: &str) {
+        for chunk in split_message(text, MAX_MESSAGE_LEN, None) {
+            self.outbox.push(msg.channel_id(), chunk);
+        }
+    }
+
+    /// Like `queue`, but prefixes the message with a @mention of the user
+    /// who sent the message.
+    pub fn queue_reply(&self, msg: &Message, text: &str) {
+        self.queue(msg, &format!("{} {}", msg.author().mention(), text));
+    }
+
+    /// The number of queued messages still waiting to be sent.
+    pub fn queue_depth(&self) -> usize {
+        self.outbox.depth()
+    }
+
+    /// Blocks until every queued message has been sent or `timeout` has
+    /// passed. Returns `false` on timeout.
+    pub fn flush_queue(&self, timeout: Duration) -> bool {
+        self.outbox.flush(timeout)
+    }
+
+    /// Sends an embed to the same channel in which the message was received.
+    pub fn send_embed(&self, msg: &Message, embed: &Embed)
+        -> error::Result<MessageId> {
+        self.send_limited(msg.channel_id(), || {
+            self.inner.send_embed(msg.channel_id(), "", embed).map(|m| m.id)
+        })
+    }
+
+    /// Sends an embed to the same channel in which the message was received,
+    /// with a @mention of the user who sent the message above it.
+    pub fn reply_embed(&self, msg: &Message, embed: &Embed)
+        -> error::Result<MessageId> {
+        let mention = format!("{}", msg.author().mention());
+        self.send_limited(msg.channel_id(), || {
+            self.inner.send_embed(msg.channel_id(), &mention, embed)
+                .map(|m| m.id)
+        })
+    }
+
+    /// Uploads a file from disk to the same channel in which the message was
+    /// received, with an optional caption. The file keeps its name.
+    pub fn send_file<P: AsRef<Path>>(&self, msg: &Message, path: P,
+                                     caption: Option<&str>)
+        -> error::Result<MessageId> {
+        let path = path.as_ref();
+        let size = path.metadata()?.len();
+        if size > MAX_FILE_SIZE {
+            return Err(BotError::FileTooLarge(size))
+        }
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let filename = path.file_name()
+            .map_or("file".to_string(), |n| n.to_string_lossy().into_owned());
+        self.send_bytes(msg, &bytes, &filename, caption)
+    }
+
+    /// Uploads `bytes` as a file named `filename` to the same channel in which
+    /// the message was received, with an optional caption.
+    pub fn send_bytes(&self, msg: &Message, bytes: &[u8], filename: &str,
+                      caption: Option<&str>) -> error::Result<MessageId> {
+        let size = bytes.len() as u64;
+        if size > MAX_FILE_SIZE {
+            return Err(BotError::FileTooLarge(size))
+        }
+        let caption = caption.unwrap_or("");
+        let len = caption.chars().count();
+        if len > MAX_MESSAGE_LEN {
+            return Err(BotError::TooLong(len))
+        }
+        self.send_limited(msg.channel_id(), || {
+            self.inner.send_file(msg.channel_id(), caption, bytes, filename)
+                .map(|m| m.id)
+        })
+    }
+
+    /// Replaces the text of a message the bot sent in the same channel in
+    /// which the message was received.
+    pub fn edit(&self, msg: &Message, id: MessageId, text: &str)
+        -> error::Result<()> {
+        let len = text.chars().count();
+        if len > MAX_MESSAGE_LEN {
+            return Err(BotError::TooLong(len))
+        }
+        self.send_limited(msg.channel_id(), || {
+            self.inner.edit_message(msg.channel_id(), id, text).map(|_| ())
+        })
+    }
+
+    /// Deletes a message in the same channel in which the message was
+    /// received. Deleting someone else's message needs the Manage Messages
+    /// permission, otherwise this fails with `PermissionDenied`.
+    pub fn delete(&self, msg: &Message, id: MessageId) -> error::Result<()> {
+        self.send_limited(msg.channel_id(),
+                          || self.inner.delete_message(msg.channel_id(), id))
+    }
+
+    /// Reacts to a message in the same channel in which the message was
+    /// received.
+    pub fn react(&self, msg: &Message, id: MessageId, emoji: &ReactionEmoji)
+        -> error::Result<()> {
+        self.send_limited(msg.channel_id(), || {
+            self.inner.add_reaction(msg.channel_id(), id, emoji)
+        })
+    }
+
+    /// Removes the bot's own reaction from a message in the same channel in
+    /// which the message was received.
+    pub fn unreact(&self, msg: &Message, id: MessageId, emoji: &ReactionEmoji)
+        -> error::Result<()> {
+        self.remove_reaction(msg, id, None, emoji)
+    }
+
+    /// Removes a user's reaction from a message in the same channel in which
+    /// the message was received, or the bot's own if `user` is `None`.
+    /// Removing someone else's reaction needs the Manage Messages permission.
+    pub fn remove_reaction(&self, msg: &Message, id: MessageId,
+                           user: Option<UserId>, emoji: &ReactionEmoji)
+        -> error::Result<()> {
+        self.send_limited(msg.channel_id(), || {
+            self.inner.remove_reaction(msg.channel_id(), id, user, emoji)
+        })
+    }
+
+    /// Pins a message in the same channel in which the message was received.
+    pub fn pin(&self, msg: &Message, id: MessageId) -> error::Result<()> {
+        self.send_limited(msg.channel_id(),
+                          || self.inner.pin_message(msg.channel_id(), id))
+    }
+
+    pub fn unpin(&self, msg: &Message, id: MessageId) -> error::Result<()> {
+        self.send_limited(msg.channel_id(),
+                          || self.inner.unpin_message(msg.channel_id(), id))
+    }
+
+    /// Shows the bot as typing in a channel for a few seconds. See
+    /// `typing::Typing` to keep it up for longer.
+    pub fn broadcast_typing(&self, channel: ChannelId) -> error::Result<()> {
+        self.inner.broadcast_typing(channel).map_err(|e| e.into())
+    }
+
+    fn send_text(&self, channel: ChannelId, text: &str)
+        -> error::Result<MessageId> {
+        let len = text.chars().count();
+        if len > MAX_MESSAGE_LEN {
+            return Err(BotError::TooLong(len))
+        }
+        self.send_limited(channel, || {
+            self.inner.send_message(channel, text).map(|m| m.id)
+        })
+    }
+
+    /// Makes a request now, waiting first if the channel is rate limited and
+    /// retrying if Discord says it was.
+    fn send_limited<T, F>(&self, channel: ChannelId, send: F)
+        -> error::Result<T>
+        where F: Fn() -> Result<T, DiscordError> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            self.outbox.acquire(channel);
+            match send() {
+                Ok(result) => return Ok(result),
+                Err(DiscordError::RateLimited(ms)) if attempts < MAX_SEND_ATTEMPTS => {
+                    self.outbox.rate_limited(channel, ms);
+                }
+                Err(err) => return Err(err.into())
+            }
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Message {
-    inner: Arc<Mutex<DiscordMessage>>
+    inner: Arc<Mutex<DiscordMessage>>,
+    server_id: Option<ServerId>,
+    prefix: String,
+    mention: Option<UserId>,
+    permissions: Option<Permissions>,
+    roles: Vec<Role>,
+    server_owner: bool,
+    owner: bool
 }
 
 impl Message {
     pub fn new(msg: DiscordMessage) -> Message {
+        Message::with_server(msg, None)
+    }
+
+    /// Creates a `Message` that is known to have been sent in a server.
+    pub fn with_server(msg: DiscordMessage, server_id: Option<ServerId>)
+        -> Message {
         Message {
-            inner: Arc::new(Mutex::new(msg))
+            inner: Arc::new(Mutex::new(msg)),
+            server_id: server_id,
+            prefix: DEFAULT_PREFIX.to_string(),
+            mention: None,
+            permissions: None,
+            roles: Vec::new(),
+            server_owner: false,
+            owner: false
         }
     }
 
+    /// Sets the prefix commands start with where the message was sent, and
+    /// the bot's user, whose @mention also counts as a prefix.
+    pub fn set_prefix(&mut self, prefix: String, mention: Option<UserId>) {
+        self.prefix = prefix;
+        self.mention = mention;
+    }
+
+    /// Sets what the author may do in the channel the message was sent in.
+    pub fn set_permissions(&mut self, permissions: Option<Permissions>) {
+        self.permissions = permissions;
+    }
+
+    /// Sets the roles the author has in the server.
+    pub fn set_roles(&mut self, roles: Vec<Role>) {
+        self.roles = roles;
+    }
+
+    /// Marks the author as the owner of the server.
+    pub fn set_server_owner(&mut self, owner: bool) {
+        self.server_owner = owner;
+    }
+
+    /// Marks the author as one of the bot's owners.
+    pub fn set_owner(&mut self, owner: bool) {
+        self.owner = owner;
+    }
+
     pub fn id(&self) -> MessageId {
         self.message().id
     }
@@ -174,10 +1127,62 @@ impl Message {
         self.message().channel_id
     }
 
+    /// The server the message was sent in, or `None` for private and group
+    /// messages.
+    pub fn server_id(&self) -> Option<ServerId> {
+        self.server_id
+    }
+
     pub fn content(&self) -> String {
         self.message().content
     }
 
+    /// The prefix commands start with where the message was sent.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns the content after the command prefix or an @mention of the
+    /// bot, or `None` if the message starts with neither.
+    pub fn command(&self) -> Option<String> {
+        let content = self.content();
+        if content.starts_with(&self.prefix) {
+            return Some(content[self.prefix.len()..].to_string())
+        }
+        let user = match self.mention {
+            Some(user) => user,
+            None => return None
+        };
+        for mention in &[format!("<@{}>", user.0), format!("<@!{}>", user.0)] {
+            if content.starts_with(mention.as_str()) {
+                return Some(content[mention.len()..].trim_left().to_string())
+            }
+        }
+        None
+    }
+
+    /// What the author may do in the channel the message was sent in, or
+    /// `None` outside of servers.
+    pub fn permissions(&self) -> Option<Permissions> {
+        self.permissions
+    }
+
+    /// The roles the author has in the server, which are none outside of
+    /// servers.
+    pub fn roles(&self) -> &[Role] {
+        &self.roles
+    }
+
+    /// Whether the author owns the server the message was sent in.
+    pub fn is_server_owner(&self) -> bool {
+        self.server_owner
+    }
+
+    /// Whether the author is one of the `owners` in the config.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
     pub fn nonce(&self) -> Option<String> {
         self.message().nonce
     }
@@ -231,3 +1236,461 @@ impl Message {
         self.inner.lock().unwrap().clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::iter;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Sender, Receiver};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use discord::model::{ChannelId, Event, MessageId, ReactionEmoji};
+    use plugin::{self, Events, Plugin};
+    use error::Result;
+    use transport::{Action, FakeTransport, fake_ready, fake_user, test_connection,
+                    test_message};
+    use super::*;
+
+    /// Replies to `!echo <text>` with the text.
+    struct Echo;
+
+    impl Plugin for Echo {
+        fn new() -> Box<Plugin> {
+            Box::new(Echo)
+        }
+
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn is_match(&self, msg: &Message) -> bool {
+            msg.command().map_or(false, |c| c.starts_with("echo "))
+        }
+
+        fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+            let text = msg.command().unwrap()["echo ".len()..].to_string();
+            conn.reply(msg, &text).map(|_| ())
+        }
+    }
+
+    /// Records which of its hooks were called.
+    struct Hooks {
+        name: &'static str,
+        events: Events,
+        calls: Arc<Mutex<Vec<String>>>
+    }
+
+    impl Hooks {
+        fn named(name: &'static str, events: Events,
+                 calls: &Arc<Mutex<Vec<String>>>) -> Box<Plugin> {
+            Box::new(Hooks { name: name, events: events, calls: calls.clone() })
+        }
+
+        fn record(&self, call: &str) {
+            self.calls.lock().unwrap().push(format!("{} {}", self.name, call));
+        }
+    }
+
+    impl Plugin for Hooks {
+        fn new() -> Box<Plugin> {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            Hooks::named("hooks", Events::none(), &calls)
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn events(&self) -> Events {
+            self.events
+        }
+
+        fn is_match(&self, _msg: &Message) -> bool {
+            false
+        }
+
+        fn handle(&self, _msg: &Message, _conn: &Connection) -> Result<()> {
+            Ok(())
+        }
+
+        fn on_ready(&self, state: &State, _conn: &Connection) {
+            self.record(&format!("ready as {}", state.user().username));
+        }
+
+        fn on_shutdown(&self, _conn: &Connection) {
+            self.record("shut down");
+        }
+
+        fn on_message_delete(&self, _channel: ChannelId, message: MessageId,
+                             _conn: &Connection) {
+            self.record(&format!("deleted {}", message.0));
+        }
+    }
+
+    /// Handles `!wait` once it is let through, by a send on the other end of
+    /// `gate` or by that end being dropped.
+    struct Gated {
+        calls: Arc<Mutex<Vec<String>>>,
+        gate: Mutex<Receiver<()>>
+    }
+
+    impl Gated {
+        fn with_calls(calls: &Arc<Mutex<Vec<String>>>)
+            -> (Box<Plugin>, Sender<()>) {
+            let (open, gate) = mpsc::channel();
+            let plugin = Gated { calls: calls.clone(), gate: Mutex::new(gate) };
+            (Box::new(plugin), open)
+        }
+    }
+
+    impl Plugin for Gated {
+        fn new() -> Box<Plugin> {
+            Gated::with_calls(&Arc::new(Mutex::new(Vec::new()))).0
+        }
+
+        fn name(&self) -> &'static str {
+            "gated"
+        }
+
+        fn is_match(&self, msg: &Message) -> bool {
+            msg.content() == "!wait"
+        }
+
+        fn handle(&self, _msg: &Message, _conn: &Connection) -> Result<()> {
+            let _ = self.gate.lock().unwrap().recv();
+            self.calls.lock().unwrap().push("handled".to_string());
+            Ok(())
+        }
+
+        fn on_shutdown(&self, _conn: &Connection) {
+            self.calls.lock().unwrap().push("shut down".to_string());
+        }
+    }
+
+    /// Has a `!secret` command that nobody can use.
+    struct Secret;
+
+    impl Plugin for Secret {
+        fn new() -> Box<Plugin> {
+            Box::new(Secret)
+        }
+
+        fn name(&self) -> &'static str {
+            "secret"
+        }
+
+        fn commands(&self) -> Vec<Command> {
+            vec![Command::new("secret")]
+        }
+
+        fn can_use(&self, _command: &Command, _msg: &Message) -> bool {
+            false
+        }
+
+        fn is_match(&self, msg: &Message) -> bool {
+            msg.command().map_or(false, |c| c == "secret")
+        }
+
+        fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+            conn.reply(msg, "the secret").map(|_| ())
+        }
+    }
+
+    /// Takes every message ahead of the other plugins, and consumes it.
+    struct Greedy;
+
+    impl Plugin for Greedy {
+        fn new() -> Box<Plugin> {
+            Box::new(Greedy)
+        }
+
+        fn name(&self) -> &'static str {
+            "greedy"
+        }
+
+        fn is_match(&self, _msg: &Message) -> bool {
+            true
+        }
+
+        fn priority(&self) -> i32 {
+            1
+        }
+
+        fn consumes(&self, _msg: &Message) -> bool {
+            true
+        }
+
+        fn handle(&self, _msg: &Message, _conn: &Connection) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A `Bot` that connects to a `FakeTransport` as user 0, and gives up
+    /// as soon as the connection is closed.
+    fn connected_bot() -> (Bot, Arc<FakeTransport>) {
+        let fake = Arc::new(FakeTransport::with_ready(
+            fake_ready(fake_user(0, "bot"))));
+        let mut bot = Bot::with_transport(fake.clone());
+        bot.set_max_retries(Some(0));
+        (bot, fake)
+    }
+
+    #[test]
+    fn reply_split_mentions_the_author_once() {
+        let (fake, conn) = test_connection();
+        let msg = test_message("!long");
+        let a = iter::repeat("a").take(1500).collect::<String>();
+        let b = iter::repeat("b").take(1500).collect::<String>();
+        conn.reply_split(&msg, &format!("{}\n{}", a, b), None).unwrap();
+
+        let sent = fake.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].1, format!("<@1> {}", a));
+        assert_eq!(sent[1].1, b);
+        assert!(sent.iter().all(|&(_, ref text)| {
+            text.chars().count() <= MAX_MESSAGE_LEN
+        }));
+    }
+
+    #[test]
+    fn a_rate_limited_send_waits_and_is_retried() {
+        let (fake, conn) = test_connection();
+        fake.rate_limit_next(100);
+        let start = Instant::now();
+        conn.reply(&test_message("!hi"), "hi").unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(fake.sent(), vec![(ChannelId(1), "<@1> hi".to_string())]);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let mut retries = Retries::new(None);
+        let delays = (0..4).map(|_| retries.next_delay().unwrap())
+            .collect::<Vec<u64>>();
+        assert_eq!(delays, vec![1000, 2000, 4000, 8000]);
+        for _ in 0..100 {
+            retries.next_delay();
+        }
+        assert_eq!(retries.next_delay(), Some(MAX_BACKOFF_MS));
+    }
+
+    #[test]
+    fn only_a_stable_session_resets_the_retries() {
+        let mut retries = Retries::new(Some(2));
+        assert!(retries.next_delay().is_some());
+        retries.session_ended(Duration::from_secs(1));
+        assert!(retries.next_delay().is_some());
+        retries.session_ended(Duration::from_secs(1));
+        assert_eq!(retries.next_delay(), None);
+
+        retries.session_ended(Duration::from_secs(STABLE_SESSION_SECS));
+        assert_eq!(retries.next_delay(), Some(INITIAL_BACKOFF_MS));
+    }
+
+    #[test]
+    fn connect_gives_up_after_the_retry_limit() {
+        // Without a `ReadyEvent` every connect fails.
+        let mut bot = Bot::with_transport(Arc::new(FakeTransport::new()));
+        bot.set_max_retries(Some(0));
+        let start = Instant::now();
+        assert!(!bot.connect());
+        assert!(start.elapsed() < Duration::from_millis(INITIAL_BACKOFF_MS));
+    }
+
+    #[test]
+    fn a_full_queue_turns_messages_away() {
+        for &(policy, told) in &[(FullPolicy::Busy, true),
+                                 (FullPolicy::Drop, false)] {
+            let (mut bot, fake) = connected_bot();
+            // Without workers the first message stays queued.
+            bot.set_pool(0, 1, policy);
+            bot.register(Echo::new());
+            bot.dispatch(test_message("!echo a"));
+            bot.dispatch(test_message("!echo b"));
+            assert!(bot.conn.flush_queue(Duration::from_secs(5)));
+
+            let busy = (ChannelId(1),
+                        "<@1> I'm busy right now, try again in a bit"
+                        .to_string());
+            assert_eq!(fake.sent() == vec![busy], told);
+            let stats = bot.queue_stats().into_iter()
+                .find(|&(name, _)| name == "echo").unwrap().1;
+            assert_eq!((stats.queued, stats.rejected), (1, 1));
+        }
+    }
+
+    #[test]
+    fn a_consumer_with_a_full_queue_passes_messages_on() {
+        let (mut bot, _) = connected_bot();
+        bot.set_pool(0, 1, FullPolicy::Drop);
+        bot.register(Echo::new());
+        bot.register(Greedy::new());
+        bot.dispatch(test_message("!echo a"));
+        bot.dispatch(test_message("!echo b"));
+
+        let stats = |plugin| {
+            let stats = bot.queue_stats().into_iter()
+                .find(|&(name, _)| name == plugin).unwrap().1;
+            (stats.queued, stats.rejected)
+        };
+        assert_eq!(stats("greedy"), (1, 1));
+        assert_eq!(stats("echo"), (1, 0));
+    }
+
+    #[test]
+    fn messages_from_the_gateway_reach_the_plugins() {
+        let (mut bot, fake) = connected_bot();
+        bot.register(Echo::new());
+        fake.inject_message(ChannelId(7), fake_user(1, "user"), "!echo hi");
+        fake.inject_message(ChannelId(7), fake_user(1, "user"), "<@0> echo yo");
+        fake.inject_message(ChannelId(7), fake_user(1, "user"), "echo no");
+        fake.close();
+
+        // Not stopped, so it gave up reconnecting once the stream closed.
+        assert!(!bot.connect());
+        assert_eq!(fake.sent(), vec![(ChannelId(7), "<@1> hi".to_string()),
+                                     (ChannelId(7), "<@1> yo".to_string())]);
+    }
+
+    #[test]
+    fn events_reach_only_the_plugins_that_asked_for_them() {
+        let (mut bot, fake) = connected_bot();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        bot.register(Hooks::named("watcher", plugin::MESSAGE_DELETES, &calls));
+        bot.register(Hooks::named("other", Events::none(), &calls));
+        fake.inject(Event::MessageDelete {
+            channel_id: ChannelId(7),
+            message_id: MessageId(3)
+        });
+        fake.close();
+
+        assert!(!bot.connect());
+        assert_eq!(*calls.lock().unwrap(), vec!["watcher deleted 3"]);
+    }
+
+    #[test]
+    fn plugins_hear_about_the_session_starting_and_ending() {
+        let (mut bot, fake) = connected_bot();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        bot.register(Hooks::named("life", Events::none(), &calls));
+        fake.close();
+
+        assert!(!bot.connect());
+        assert_eq!(*calls.lock().unwrap(), vec!["life ready as bot",
+                                                "life shut down"]);
+    }
+
+    #[test]
+    fn stopping_waits_for_queued_handlers() {
+        let (mut bot, _) = connected_bot();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (plugin, open) = Gated::with_calls(&calls);
+        bot.register(plugin);
+        bot.dispatch(test_message("!wait"));
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let _ = open.send(());
+        });
+
+        bot.stop();
+        assert!(bot.connect());
+        assert_eq!(*calls.lock().unwrap(), vec!["handled", "shut down"]);
+    }
+
+    #[test]
+    fn stopping_gives_up_on_handlers_after_the_timeout() {
+        let (mut bot, _) = connected_bot();
+        bot.set_shutdown_timeout(Duration::from_secs(0));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (plugin, open) = Gated::with_calls(&calls);
+        bot.register(plugin);
+        bot.dispatch(test_message("!wait"));
+
+        bot.stop();
+        assert!(!bot.connect());
+        assert_eq!(*calls.lock().unwrap(), vec!["shut down"]);
+        open.send(()).unwrap();
+    }
+
+    #[test]
+    fn uploads_files_within_the_size_limit() {
+        let (fake, conn) = test_connection();
+        let msg = test_message("!file");
+        let big = vec![0; MAX_FILE_SIZE as usize + 1];
+        match conn.send_bytes(&msg, &big, "big.bin", None) {
+            Err(BotError::FileTooLarge(size)) => {
+                assert_eq!(size, MAX_FILE_SIZE + 1)
+            }
+            _ => panic!("a file over the limit was not refused")
+        }
+
+        let path = env::temp_dir().join("bot-send-file-test.txt");
+        File::create(&path).unwrap().write_all(b"hello").unwrap();
+        conn.send_file(&msg, &path, Some("here")).unwrap();
+        File::create(&path).unwrap().set_len(MAX_FILE_SIZE + 1).unwrap();
+        let too_large = conn.send_file(&msg, &path, None);
+        fs::remove_file(&path).unwrap();
+
+        match too_large {
+            Err(BotError::FileTooLarge(_)) => {}
+            _ => panic!("a file over the limit was not refused")
+        }
+        assert_eq!(fake.files(), vec![(ChannelId(1),
+                                       "bot-send-file-test.txt".to_string(),
+                                       b"hello".to_vec())]);
+        assert_eq!(fake.sent(), vec![(ChannelId(1), "here".to_string())]);
+    }
+
+    #[test]
+    fn edits_deletions_reactions_and_pins_are_recorded() {
+        let (fake, conn) = test_connection();
+        let msg = test_message("!act");
+        let id = conn.send(&msg, "first").unwrap();
+        let thumbs = ReactionEmoji::Unicode("👍".to_string());
+        conn.edit(&msg, id, "second").unwrap();
+        conn.react(&msg, id, &thumbs).unwrap();
+        conn.unreact(&msg, id, &thumbs).unwrap();
+        conn.pin(&msg, id).unwrap();
+        conn.unpin(&msg, id).unwrap();
+        conn.delete(&msg, id).unwrap();
+
+        let actions = fake.actions();
+        assert_eq!(actions.len(), 6);
+        match actions[0] {
+            Action::Edit(ChannelId(1), m, ref text) => {
+                assert_eq!((m, text.as_str()), (id, "second"))
+            }
+            ref other => panic!("expected an edit, got {:?}", other)
+        }
+        match actions[1] {
+            Action::React(ChannelId(1), m, ReactionEmoji::Unicode(ref e)) => {
+                assert_eq!((m, e.as_str()), (id, "👍"))
+            }
+            ref other => panic!("expected a reaction, got {:?}", other)
+        }
+        match actions[2] {
+            Action::Unreact(ChannelId(1), m, None, _) => assert_eq!(m, id),
+            ref other => panic!("expected an unreaction, got {:?}", other)
+        }
+        match (&actions[3], &actions[4], &actions[5]) {
+            (&Action::Pin(_, a), &Action::Unpin(_, b),
+             &Action::Delete(_, c)) => assert_eq!((a, b, c), (id, id, id)),
+            other => panic!("expected pin, unpin, delete, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn commands_the_plugin_refuses_are_not_handled() {
+        let (mut bot, fake) = connected_bot();
+        bot.register(Secret::new());
+        bot.dispatch(test_message("!secret"));
+        bot.pool.wait_idle();
+        assert!(bot.conn.flush_queue(Duration::from_secs(5)));
+        assert_eq!(fake.sent(), vec![(ChannelId(1),
+                                      "<@1> Sorry, you can't use `!secret`"
+                                      .to_string())]);
+    }
+}
@@ -0,0 +1,565 @@
+use std::fmt;
+use discord::model::{ChannelId, UserId};
+use access::Access;
+use bot::{Connection, Message};
+use error::Result;
+
+/// The kinds of argument a command can take.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    /// A whole number.
+    Int,
+    /// A single word.
+    Word,
+    /// A "quoted string", or a single word without quotes.
+    Quoted,
+    /// A user @mention.
+    User,
+    /// A #channel mention.
+    Channel,
+    /// Everything left on the line. Must come last.
+    Rest,
+    /// Exactly this word, to tell apart commands that share a name.
+    Literal(&'static str)
+}
+
+#[derive(Clone, Debug)]
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool
+}
+
+/// A parsed argument.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+    User(UserId),
+    Channel(ChannelId)
+}
+
+/// The arguments a command was called with, by name. Required arguments are
+/// always present, so their accessors can be unwrapped.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    values: Vec<(&'static str, Value)>
+}
+
+impl Args {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.iter().find(|&&(n, _)| n == name).map(|&(_, ref v)| v)
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        match self.get(name) {
+            Some(&Value::Int(i)) => Some(i),
+            _ => None
+        }
+    }
+
+    /// A `Word`, `Quoted`, `Rest` or `Literal` argument.
+    pub fn str(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(&Value::Str(ref s)) => Some(s.as_str()),
+            _ => None
+        }
+    }
+
+    pub fn user(&self, name: &str) -> Option<UserId> {
+        match self.get(name) {
+            Some(&Value::User(id)) => Some(id),
+            _ => None
+        }
+    }
+
+    pub fn channel(&self, name: &str) -> Option<ChannelId> {
+        match self.get(name) {
+            Some(&Value::Channel(id)) => Some(id),
+            _ => None
+        }
+    }
+}
+
+/// Why a message did not fit a command's arguments.
+#[derive(Clone, Debug)]
+pub enum ArgError {
+    Missing(&'static str),
+    Invalid(&'static str, ArgKind),
+    Unexpected(String)
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArgError::Missing(name) => write!(f, "Missing <{}>", name),
+            ArgError::Invalid(name, kind) => match kind {
+                ArgKind::Int => write!(f, "<{}> must be a number", name),
+                ArgKind::User => write!(f, "<{}> must be a @mention", name),
+                ArgKind::Channel => write!(f, "<{}> must be a #channel", name),
+                ArgKind::Quoted => write!(f, "<{}> is missing a closing quote",
+                                          name),
+                ArgKind::Literal(word) => write!(f, "Expected `{}`", word),
+                _ => write!(f, "<{}> is not valid", name)
+            },
+            ArgError::Unexpected(ref text) => write!(f, "Unexpected `{}`", text)
+        }
+    }
+}
+
+/// A command's name, aliases, arguments, description and who may use it.
+/// Build one with `Command::new` and the builder methods, then add it to a
+/// `Router`.
+///
+/// Arguments are separated by spaces or a comma. Optional arguments must
+/// come after the required ones.
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: Vec<&'static str>,
+    pub args: Vec<Arg>,
+    pub description: &'static str,
+    pub access: Access
+}
+
+impl Command {
+    pub fn new(name: &'static str) -> Command {
+        Command {
+            name: name,
+            aliases: Vec::new(),
+            args: Vec::new(),
+            description: "",
+            access: Access::Everyone
+        }
+    }
+
+    pub fn alias(mut self, alias: &'static str) -> Command {
+        self.aliases.push(alias);
+        self
+    }
+
+    pub fn arg(mut self, name: &'static str, kind: ArgKind) -> Command {
+        self.args.push(Arg {
+            name: name,
+            kind: kind,
+            optional: false
+        });
+        self
+    }
+
+    pub fn optional(mut self, name: &'static str, kind: ArgKind) -> Command {
+        self.args.push(Arg {
+            name: name,
+            kind: kind,
+            optional: true
+        });
+        self
+    }
+
+    pub fn description(mut self, description: &'static str) -> Command {
+        self.description = description;
+        self
+    }
+
+    /// Limits who may use the command. See `access::AccessRules`.
+    pub fn access(mut self, access: Access) -> Command {
+        self.access = access;
+        self
+    }
+
+    /// Returns whether the command is called `name` or has it as an alias.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|&a| a == name)
+    }
+
+    /// Returns how to call the command, e.g. `!9a <episode> <title...>`.
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{}{}", prefix, self.name);
+        for arg in &self.args {
+            let arg = match arg.kind {
+                ArgKind::Literal(word) => word.to_string(),
+                ArgKind::Rest if arg.optional => format!("[{}...]", arg.name),
+                ArgKind::Rest => format!("<{}...>", arg.name),
+                _ if arg.optional => format!("[{}]", arg.name),
+                _ => format!("<{}>", arg.name)
+            };
+            usage.push(' ');
+            usage.push_str(&arg);
+        }
+        usage
+    }
+
+    /// Parses the text following the command's name.
+    pub fn parse(&self, input: &str) -> ::std::result::Result<Args, ArgError> {
+        let mut args = Args::default();
+        let mut rest = input;
+        for arg in &self.args {
+            rest = skip_separator(rest);
+            if rest.is_empty() {
+                if arg.optional { break }
+                return Err(ArgError::Missing(arg.name))
+            }
+            let (value, remaining) = parse_arg(arg, rest)?;
+            args.values.push((arg.name, value));
+            rest = remaining;
+        }
+
+        let rest = skip_separator(rest);
+        if !rest.is_empty() {
+            return Err(ArgError::Unexpected(rest.to_string()))
+        }
+        Ok(args)
+    }
+}
+
+/// Called with the plugin, the parsed arguments and the message.
+pub type Handler<P> = fn(&P, &Args, &Message, &Connection) -> Result<()>;
+
+/// A `Router` matches messages against a plugin's commands and calls the
+/// handler of the one that fits. A plugin keeps one and forwards `is_match`
+/// and `handle` to it.
+///
+/// Several commands may share a name, as long as their arguments tell them
+/// apart; the first one that parses wins. If none does, the user is told
+/// what was wrong and how to call the command.
+pub struct Router<P> {
+    routes: Vec<(Command, Handler<P>)>
+}
+
+impl<P> Router<P> {
+    pub fn new() -> Router<P> {
+        Router {
+            routes: Vec::new()
+        }
+    }
+
+    pub fn add(mut self, command: Command, handler: Handler<P>) -> Router<P> {
+        self.routes.push((command, handler));
+        self
+    }
+
+    pub fn commands(&self) -> Vec<Command> {
+        self.routes.iter().map(|&(ref c, _)| c.clone()).collect()
+    }
+
+    /// Returns whether the message calls one of the commands, whether or
+    /// not its arguments fit.
+    pub fn is_match(&self, msg: &Message) -> bool {
+        let command = match msg.command() {
+            Some(command) => command,
+            None => return false
+        };
+        match split_name(&command) {
+            Some((name, _)) => {
+                self.routes.iter().any(|&(ref c, _)| c.is_named(name))
+            }
+            None => false
+        }
+    }
+
+    /// Parses the message and calls the matching handler, or replies with a
+    /// usage error.
+    pub fn dispatch(&self, plugin: &P, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let command = match msg.command() {
+            Some(command) => command,
+            None => return Ok(())
+        };
+        let (name, input) = match split_name(&command) {
+            Some(split) => split,
+            None => return Ok(())
+        };
+
+        let mut error = None;
+        let mut usages = Vec::new();
+        for &(ref command, handler) in &self.routes {
+            if !command.is_named(name) { continue }
+            match command.parse(input) {
+                Ok(args) => return handler(plugin, &args, msg, conn),
+                Err(err) => {
+                    // A wrong literal only means another command with the
+                    // same name was meant, so prefer any other error.
+                    let replace = match error {
+                        None => true,
+                        Some(ref e) => is_literal(e) && !is_literal(&err)
+                    };
+                    if replace {
+                        error = Some(err);
+                    }
+                    usages.push(format!("`{}`", command.usage(msg.prefix())));
+                }
+            }
+        }
+
+        match error {
+            Some(err) => {
+                conn.reply(msg, &format!("{}. Usage: {}", err,
+                                         usages.join(" or ")))?;
+                Ok(())
+            }
+            None => Ok(())
+        }
+    }
+}
+
+/// Returns which of `commands` the message calls: the first with the right
+/// name whose arguments fit, or else the first with the right name.
+pub fn resolve(commands: Vec<Command>, msg: &Message) -> Option<Command> {
+    let command = match msg.command() {
+        Some(command) => command,
+        None => return None
+    };
+    let (name, input) = match split_name(&command) {
+        Some(split) => split,
+        None => return None
+    };
+    let named = commands.into_iter()
+        .filter(|c| c.is_named(name))
+        .collect::<Vec<Command>>();
+    let fits = named.iter().position(|c| c.parse(input).is_ok()).unwrap_or(0);
+    named.into_iter().nth(fits)
+}
+
+/// Splits the text after a command prefix into the command name and the
+/// text after it, or returns `None` if there is no name.
+fn split_name(content: &str) -> Option<(&str, &str)> {
+    let end = content.find(char::is_whitespace).unwrap_or(content.len());
+    if end == 0 {
+        return None
+    }
+    Some((&content[..end], &content[end..]))
+}
+
+fn is_literal(err: &ArgError) -> bool {
+    match *err {
+        ArgError::Invalid(_, ArgKind::Literal(_)) => true,
+        _ => false
+    }
+}
+
+fn skip_separator(input: &str) -> &str {
+    let input = input.trim_left();
+    if input.starts_with(',') {
+        input[1..].trim_left()
+    } else {
+        input
+    }
+}
+
+fn parse_arg<'a>(arg: &Arg, input: &'a str)
+    -> ::std::result::Result<(Value, &'a str), ArgError> {
+    let invalid = ArgError::Invalid(arg.name, arg.kind);
+    if arg.kind == ArgKind::Rest {
+        return Ok((Value::Str(input.trim_right().to_string()), ""))
+    }
+    if arg.kind == ArgKind::Quoted && input.starts_with('"') {
+        return quoted(&input[1..]).ok_or(invalid)
+    }
+
+    let (word, rest) = next_word(input);
+    let value = match arg.kind {
+        ArgKind::Int => word.parse().ok().map(Value::Int),
+        ArgKind::Word | ArgKind::Quoted | ArgKind::Rest => {
+            Some(Value::Str(word.to_string()))
+        }
+        ArgKind::User => mention_id(word, "<@").map(|id| Value::User(UserId(id))),
+        ArgKind::Channel => {
+            mention_id(word, "<#").map(|id| Value::Channel(ChannelId(id)))
+        }
+        ArgKind::Literal(expected) if word == expected => {
+            Some(Value::Str(word.to_string()))
+        }
+        ArgKind::Literal(_) => None
+    };
+    value.map(|v| (v, rest)).ok_or(invalid)
+}
+
+/// Splits off the next word, which ends at whitespace or a comma.
+fn next_word(input: &str) -> (&str, &str) {
+    match input.find(|c: char| c.is_whitespace() || c == ',') {
+        Some(i) => (&input[..i], &input[i..]),
+        None => (input, "")
+    }
+}
+
+/// Reads a quoted string up to its closing quote, given the text after the
+/// opening one. A backslash escapes the next character.
+fn quoted(input: &str) -> Option<(Value, &str)> {
+    let mut text = String::new();
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if escaped {
+            text.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Some((Value::Str(text), &input[i + 1..]))
+        } else {
+            text.push(c);
+        }
+    }
+    None
+}
+
+/// Reads the id out of a mention such as `<@123>`, `<@!123>` or `<#123>`.
+fn mention_id(word: &str, open: &str) -> Option<u64> {
+    if !word.starts_with(open) || !word.ends_with('>') {
+        return None
+    }
+    word[open.len()..word.len() - 1].trim_left_matches('!').parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use discord::model::{ChannelId, UserId};
+    use bot::{Connection, Message};
+    use error::Result;
+    use transport::{test_connection, test_message};
+    use super::*;
+
+    /// Records which handler was called, and with what.
+    struct Calls(Mutex<Vec<String>>);
+
+    impl Calls {
+        fn record(&self, call: String) -> Result<()> {
+            self.0.lock().unwrap().push(call);
+            Ok(())
+        }
+
+        fn next(&self, _args: &Args, _msg: &Message, _conn: &Connection)
+            -> Result<()> {
+            self.record("next".to_string())
+        }
+
+        fn episode(&self, args: &Args, _msg: &Message, _conn: &Connection)
+            -> Result<()> {
+            self.record(format!("episode {} of {}", args.int("episode").unwrap(),
+                                args.str("title").unwrap()))
+        }
+
+        fn search(&self, args: &Args, _msg: &Message, _conn: &Connection)
+            -> Result<()> {
+            self.record(format!("{} in /{}/", args.str("query").unwrap(),
+                                args.str("board").unwrap()))
+        }
+
+        fn meme(&self, args: &Args, _msg: &Message, _conn: &Connection)
+            -> Result<()> {
+            self.record(format!("meme {:?}", args.str("meme")))
+        }
+    }
+
+    fn router() -> Router<Calls> {
+        Router::new()
+            .add(Command::new("9a").arg("next", ArgKind::Literal("next")),
+                 Calls::next)
+            .add(Command::new("9a")
+                 .arg("episode", ArgKind::Int)
+                 .arg("title", ArgKind::Rest),
+                 Calls::episode)
+            .add(Command::new("4c")
+                 .arg("board", ArgKind::Word)
+                 .arg("query", ArgKind::Rest),
+                 Calls::search)
+            .add(Command::new("meme").optional("meme", ArgKind::Rest),
+                 Calls::meme)
+    }
+
+    /// Dispatches each message in turn, and returns the handlers called and
+    /// the replies sent.
+    fn dispatch(contents: &[&str]) -> (Vec<String>, Vec<String>) {
+        let (fake, conn) = test_connection();
+        let calls = Calls(Mutex::new(Vec::new()));
+        let router = router();
+        for content in contents {
+            let msg = test_message(content);
+            assert!(router.is_match(&msg));
+            router.dispatch(&calls, &msg, &conn).unwrap();
+        }
+        let sent = fake.sent().into_iter().map(|(_, text)| text).collect();
+        (calls.0.into_inner().unwrap(), sent)
+    }
+
+    #[test]
+    fn arguments_are_separated_by_spaces_or_a_comma() {
+        let command = Command::new("c")
+            .arg("n", ArgKind::Int)
+            .arg("word", ArgKind::Word);
+        for input in &[" 3 title", " 3, title", " 3,title", "  3 ,  title "] {
+            let args = command.parse(input).unwrap();
+            assert_eq!(args.int("n"), Some(3));
+            assert_eq!(args.str("word"), Some("title"));
+        }
+        match command.parse(" 3 title extra") {
+            Err(ArgError::Unexpected(ref text)) => assert_eq!(text, "extra"),
+            other => panic!("expected Unexpected, got {:?}", other)
+        }
+        match command.parse(" 3") {
+            Err(ArgError::Missing("word")) => {}
+            other => panic!("expected Missing, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn quoted_strings_keep_spaces_and_escapes() {
+        let command = Command::new("c")
+            .arg("text", ArgKind::Quoted)
+            .arg("word", ArgKind::Word);
+        let args = command.parse(r#" "say \"hi\", \\ there" next"#).unwrap();
+        assert_eq!(args.str("text"), Some(r#"say "hi", \ there"#));
+        assert_eq!(args.str("word"), Some("next"));
+
+        let args = command.parse(" plain next").unwrap();
+        assert_eq!(args.str("text"), Some("plain"));
+        match command.parse(r#" "unclosed next"#) {
+            Err(ArgError::Invalid("text", ArgKind::Quoted)) => {}
+            other => panic!("expected Invalid, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn mentions_are_read_as_ids() {
+        let command = Command::new("c")
+            .arg("user", ArgKind::User)
+            .optional("channel", ArgKind::Channel);
+        assert_eq!(command.parse(" <@42>").unwrap().user("user"),
+                   Some(UserId(42)));
+        let args = command.parse(" <@!42> <#7>").unwrap();
+        assert_eq!(args.user("user"), Some(UserId(42)));
+        assert_eq!(args.channel("channel"), Some(ChannelId(7)));
+        assert!(command.parse(" @someone").is_err());
+        assert!(command.parse(" <@42> <@7>").is_err());
+    }
+
+    #[test]
+    fn the_old_syntaxes_still_work() {
+        let (calls, sent) = dispatch(&["!9a 3, title", "!9a next",
+                                       "!4c g,query", "!meme <text>",
+                                       "!meme"]);
+        assert_eq!(calls, vec!["episode 3 of title", "next", "query in /g/",
+                               "meme Some(\"<text>\")", "meme None"]);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn a_wrong_literal_loses_to_other_errors() {
+        let (calls, sent) = dispatch(&["!9a soon"]);
+        assert!(calls.is_empty());
+        assert_eq!(sent, vec!["<@1> <episode> must be a number. Usage: \
+                               `!9a next` or `!9a <episode> <title...>`"]);
+    }
+
+    #[test]
+    fn resolve_picks_the_overload_that_fits() {
+        let commands = router().commands();
+        let resolved = resolve(commands.clone(), &test_message("!9a 2 title"))
+            .unwrap();
+        assert_eq!(resolved.args[0].name, "episode");
+        let resolved = resolve(commands.clone(), &test_message("!9a soon"))
+            .unwrap();
+        assert_eq!(resolved.args[0].name, "next");
+        assert!(resolve(commands, &test_message("!nope")).is_none());
+    }
+}
@@ -0,0 +1,574 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use discord::model::UserId;
+use toml::{Parser, Table, Value};
+use executor::FullPolicy;
+use plugins;
+
+/// Where the config is read from unless `--config` says otherwise. Unlike a
+/// path given with `--config`, it may be missing.
+pub const DEFAULT_PATH: &'static str = "config.toml";
+
+/// Plugins enabled when the config does not say, in the order they are
+/// registered.
+pub const DEFAULT_PLUGINS: &'static [&'static str] =
+    &["bully", "hug", "meme", "4chan-image", "4chan", "anime"];
+
+const TOP_LEVEL_KEYS: &'static [&'static str] =
+    &["token", "token_file", "prefix", "owners", "plugins", "logging",
+      "runtime"];
+const LOGGING_KEYS: &'static [&'static str] = &["messages", "events"];
+const RUNTIME_KEYS: &'static [&'static str] =
+    &["max_retries", "workers", "queue_capacity", "when_full",
+      "handler_timeout", "shutdown_timeout"];
+
+/// A problem with the config file, and the key it is about.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String
+}
+
+impl ConfigError {
+    pub fn new(key: &str, message: &str) -> ConfigError {
+        ConfigError {
+            key: key.to_string(),
+            message: message.to_string()
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "`{}`: {}", self.key, self.message)
+        }
+    }
+}
+
+impl StdError for ConfigError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, ConfigError>;
+
+/// What is logged besides warnings and errors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Logging {
+    /// Every chat message the bot sees.
+    pub messages: bool,
+    /// Gateway events the bot does not know.
+    pub events: bool
+}
+
+impl Default for Logging {
+    fn default() -> Logging {
+        Logging {
+            messages: true,
+            events: true
+        }
+    }
+}
+
+/// How the bot runs, which only changes on a restart. `None` leaves the
+/// `Bot`'s default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Runtime {
+    /// Consecutive reconnect attempts before giving up, or `None` to retry
+    /// forever.
+    pub max_retries: Option<u32>,
+    /// The threads shared by all plugins' handlers.
+    pub workers: Option<usize>,
+    /// The matched messages each plugin may have waiting.
+    pub queue_capacity: Option<usize>,
+    /// What happens to a matched message when that queue is full.
+    pub when_full: Option<FullPolicy>,
+    /// How long a handler may run before it is reported as slow.
+    pub handler_timeout: Option<Duration>,
+    /// How long a shutdown waits for queued handlers.
+    pub shutdown_timeout: Option<Duration>
+}
+
+/// The bot's configuration, read from a TOML file such as:
+///
+/// ```toml
+/// token_file = "token.txt"
+/// prefix = "!"
+/// owners = ["80351110224678912"]
+///
+/// [logging]
+/// messages = true
+///
+/// [runtime]
+/// max_retries = 10
+/// workers = 4
+///
+/// [plugins]
+/// enabled = ["meme", "4chan"]
+///
+/// [plugins.meme]
+/// cooldown = 30
+/// ```
+///
+/// Every key is optional. Without a token the one in `DISCORD_TOKEN` is used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub token: Option<String>,
+    pub prefix: Option<String>,
+    /// The users who run the bot, and may reload its config.
+    pub owners: Vec<UserId>,
+    /// The names of the plugins to register, in order.
+    pub plugins: Vec<String>,
+    pub logging: Logging,
+    pub runtime: Runtime,
+    settings: BTreeMap<String, Settings>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            token: None,
+            prefix: None,
+            owners: Vec::new(),
+            plugins: DEFAULT_PLUGINS.iter().map(|p| p.to_string()).collect(),
+            logging: Logging::default(),
+            runtime: Runtime::default(),
+            settings: BTreeMap::new()
+        }
+    }
+}
+
+impl Config {
+    /// Reads and validates the config file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| ConfigError::new(
+                "", &format!("Could not read {}: {}", path.display(), e)))?;
+        Config::parse(&text)
+    }
+
+    /// Parses and validates a config.
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut parser = Parser::new(text);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let err = &parser.errors[0];
+                let (line, col) = parser.to_linecol(err.lo);
+                return Err(ConfigError::new(
+                    "", &format!("Line {}, column {}: {}", line + 1, col + 1,
+                                 err.desc)))
+            }
+        };
+        check_keys("", &table, TOP_LEVEL_KEYS)?;
+
+        let mut config = Config::default();
+        config.token = match (get_str(&table, "", "token")?,
+                              get_str(&table, "", "token_file")?) {
+            (Some(_), Some(_)) => {
+                return Err(ConfigError::new(
+                    "token_file",
+                    "only one of `token` and `token_file` may be set"))
+            }
+            (Some(token), None) => Some(token),
+            (None, Some(file)) => Some(read_token(&file)?),
+            (None, None) => None
+        };
+        config.prefix = get_str(&table, "", "prefix")?;
+        if config.prefix.as_ref().map_or(false, |p| p.trim().is_empty()) {
+            return Err(ConfigError::new("prefix", "must not be empty"))
+        }
+
+        config.owners = get_id_list(&table, "owners")?;
+
+        if let Some(logging) = get_table(&table, "", "logging")? {
+            check_keys("logging", logging, LOGGING_KEYS)?;
+            if let Some(messages) = get_bool(logging, "logging", "messages")? {
+                config.logging.messages = messages;
+            }
+            if let Some(events) = get_bool(logging, "logging", "events")? {
+                config.logging.events = events;
+            }
+        }
+
+        if let Some(runtime) = get_table(&table, "", "runtime")? {
+            check_keys("runtime", runtime, RUNTIME_KEYS)?;
+            config.runtime = read_runtime(runtime)?;
+        }
+
+        if let Some(plugins) = get_table(&table, "", "plugins")? {
+            if let Some(enabled) = get_str_list(plugins, "plugins", "enabled")? {
+                for (i, name) in enabled.iter().enumerate() {
+                    if !DEFAULT_PLUGINS.contains(&name.as_str()) {
+                        return Err(ConfigError::new(
+                            "plugins.enabled",
+                            &format!("there is no plugin called `{}`", name)))
+                    }
+                    if enabled[..i].contains(name) {
+                        return Err(ConfigError::new(
+                            "plugins.enabled",
+                            &format!("`{}` is listed twice", name)))
+                    }
+                }
+                config.plugins = enabled;
+            }
+            // Every plugin's table is checked, so that a typo is caught
+            // before the plugin is enabled.
+            for (name, value) in plugins {
+                if name == "enabled" { continue }
+                let key = format!("plugins.{}", name);
+                let known = match plugins::settings_keys(name) {
+                    Some(known) => known,
+                    None => {
+                        return Err(ConfigError::new(&key,
+                                                    "there is no such plugin"))
+                    }
+                };
+                match *value {
+                    Value::Table(ref settings) => {
+                        check_keys(&key, settings, known)?;
+                        config.settings.insert(name.clone(), Settings {
+                            key: key,
+                            table: settings.clone()
+                        });
+                    }
+                    _ => return Err(ConfigError::new(&key, "expected a table"))
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// The settings under `[plugins.<name>]`, which are empty if there is no
+    /// such table.
+    pub fn settings(&self, name: &str) -> Settings {
+        self.settings.get(name).cloned()
+            .unwrap_or_else(|| Settings::empty(name))
+    }
+}
+
+/// Where a `Config` is read from, so that it can be read again.
+#[derive(Clone, Debug)]
+pub struct Source {
+    path: PathBuf,
+    // Whether the file must exist, rather than falling back to the defaults.
+    required: bool
+}
+
+impl Source {
+    /// The file named by `--config PATH`, or else `config.toml` if it
+    /// exists.
+    pub fn from_args(args: &[String]) -> Result<Source> {
+        match args.iter().position(|a| a == "--config") {
+            Some(i) => match args.get(i + 1) {
+                Some(path) => Ok(Source {
+                    path: PathBuf::from(path),
+                    required: true
+                }),
+                None => Err(ConfigError::new("", "--config needs a path"))
+            },
+            None => Ok(Source {
+                path: PathBuf::from(DEFAULT_PATH),
+                required: false
+            })
+        }
+    }
+
+    pub fn load(&self) -> Result<Config> {
+        if !self.required && !self.path.exists() {
+            return Ok(Config::default())
+        }
+        Config::load(&self.path)
+    }
+}
+
+/// One plugin's settings, with getters that say which key is wrong when a
+/// value has the wrong type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    key: String,
+    table: Table
+}
+
+impl Settings {
+    pub fn empty(plugin: &str) -> Settings {
+        Settings {
+            key: format!("plugins.{}", plugin),
+            table: Table::new()
+        }
+    }
+
+    /// The full name of one of the settings, for error messages.
+    pub fn key(&self, key: &str) -> String {
+        format!("{}.{}", self.key, key)
+    }
+
+    /// Fails on the first key that is not in `known`.
+    pub fn check_keys(&self, known: &[&str]) -> Result<()> {
+        check_keys(&self.key, &self.table, known)
+    }
+
+    pub fn str(&self, key: &str) -> Result<Option<String>> {
+        get_str(&self.table, &self.key, key)
+    }
+
+    pub fn int(&self, key: &str) -> Result<Option<i64>> {
+        get_int(&self.table, &self.key, key)
+    }
+
+    pub fn bool(&self, key: &str) -> Result<Option<bool>> {
+        get_bool(&self.table, &self.key, key)
+    }
+
+    pub fn str_list(&self, key: &str) -> Result<Option<Vec<String>>> {
+        get_str_list(&self.table, &self.key, key)
+    }
+}
+
+fn read_runtime(table: &Table) -> Result<Runtime> {
+    let parent = "runtime";
+    let when_full = match get_str(table, parent, "when_full")? {
+        None => None,
+        Some(policy) => match policy.as_str() {
+            "busy" => Some(FullPolicy::Busy),
+            "drop" => Some(FullPolicy::Drop),
+            _ => {
+                return Err(ConfigError::new("runtime.when_full",
+                                            "expected \"busy\" or \"drop\""))
+            }
+        }
+    };
+    Ok(Runtime {
+        max_retries: get_at_least(table, parent, "max_retries", 0)?
+            .map(|n| cmp::min(n, u32::max_value() as u64) as u32),
+        workers: get_at_least(table, parent, "workers", 1)?
+            .map(|n| n as usize),
+        queue_capacity: get_at_least(table, parent, "queue_capacity", 1)?
+            .map(|n| n as usize),
+        when_full: when_full,
+        handler_timeout: get_secs(table, parent, "handler_timeout")?,
+        shutdown_timeout: get_secs(table, parent, "shutdown_timeout")?
+    })
+}
+
+fn join(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", parent, key)
+    }
+}
+
+fn check_keys(parent: &str, table: &Table, known: &[&str]) -> Result<()> {
+    match table.keys().find(|k| !known.contains(&k.as_str())) {
+        Some(key) => Err(ConfigError::new(&join(parent, key), "unknown key")),
+        None => Ok(())
+    }
+}
+
+fn get_str(table: &Table, parent: &str, key: &str) -> Result<Option<String>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(&Value::String(ref s)) => Ok(Some(s.clone())),
+        Some(_) => Err(ConfigError::new(&join(parent, key), "expected a string"))
+    }
+}
+
+fn get_int(table: &Table, parent: &str, key: &str) -> Result<Option<i64>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(&Value::Integer(i)) => Ok(Some(i)),
+        Some(_) => {
+            Err(ConfigError::new(&join(parent, key), "expected an integer"))
+        }
+    }
+}
+
+/// Reads a whole number that must be at least `min`.
+fn get_at_least(table: &Table, parent: &str, key: &str, min: i64)
+    -> Result<Option<u64>> {
+    match get_int(table, parent, key)? {
+        Some(i) if i < min => {
+            Err(ConfigError::new(&join(parent, key),
+                                 &format!("must be at least {}", min)))
+        }
+        i => Ok(i.map(|i| i as u64))
+    }
+}
+
+/// Reads a number of seconds.
+fn get_secs(table: &Table, parent: &str, key: &str)
+    -> Result<Option<Duration>> {
+    Ok(get_at_least(table, parent, key, 0)?.map(Duration::from_secs))
+}
+
+fn get_bool(table: &Table, parent: &str, key: &str) -> Result<Option<bool>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(&Value::Boolean(b)) => Ok(Some(b)),
+        Some(_) => {
+            Err(ConfigError::new(&join(parent, key), "expected true or false"))
+        }
+    }
+}
+
+fn get_str_list(table: &Table, parent: &str, key: &str)
+    -> Result<Option<Vec<String>>> {
+    let error = || ConfigError::new(&join(parent, key),
+                                    "expected a list of strings");
+    match table.get(key) {
+        None => Ok(None),
+        Some(&Value::Array(ref values)) => {
+            values.iter()
+                .map(|v| match *v {
+                    Value::String(ref s) => Ok(s.clone()),
+                    _ => Err(error())
+                })
+                .collect::<Result<Vec<String>>>()
+                .map(Some)
+        }
+        Some(_) => Err(error())
+    }
+}
+
+/// Reads a list of user ids, which may be written as numbers or strings.
+fn get_id_list(table: &Table, key: &str) -> Result<Vec<UserId>> {
+    let error = || ConfigError::new(key, "expected a list of user ids");
+    match table.get(key) {
+        None => Ok(Vec::new()),
+        Some(&Value::Array(ref values)) => {
+            values.iter()
+                .map(|v| match *v {
+                    Value::Integer(id) if id > 0 => Ok(UserId(id as u64)),
+                    Value::String(ref id) => {
+                        id.parse().map(UserId).map_err(|_| error())
+                    }
+                    _ => Err(error())
+                })
+                .collect()
+        }
+        Some(_) => Err(error())
+    }
+}
+
+fn get_table<'a>(table: &'a Table, parent: &str, key: &str)
+    -> Result<Option<&'a Table>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(&Value::Table(ref t)) => Ok(Some(t)),
+        Some(_) => Err(ConfigError::new(&join(parent, key), "expected a table"))
+    }
+}
+
+fn read_token(path: &str) -> Result<String> {
+    let mut token = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut token))
+        .map_err(|e| ConfigError::new(
+            "token_file", &format!("could not read {}: {}", path, e)))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(ConfigError::new("token_file", &format!("{} is empty", path)))
+    }
+    Ok(token.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::Config;
+
+    fn error(text: &str) -> (String, String) {
+        let err = Config::parse(text).unwrap_err();
+        (err.key, err.message)
+    }
+
+    fn error_at(key: &str, message: &str) -> (String, String) {
+        (key.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn reads_every_section() {
+        let config = Config::parse("prefix = \"?\"\n\
+                                    owners = [\"80351110224678912\", 7]\n\
+                                    [logging]\nmessages = false\n\
+                                    [runtime]\nworkers = 2\n\
+                                    shutdown_timeout = 5\n\
+                                    [plugins]\nenabled = [\"meme\", \"hug\"]\n\
+                                    [plugins.meme]\ncooldown = 30\n").unwrap();
+        assert_eq!(config.prefix, Some("?".to_string()));
+        assert_eq!(config.owners.len(), 2);
+        assert!(!config.logging.messages && config.logging.events);
+        assert_eq!(config.runtime.workers, Some(2));
+        assert_eq!(config.runtime.shutdown_timeout,
+                   Some(Duration::from_secs(5)));
+        assert_eq!(config.plugins, vec!["meme", "hug"]);
+        assert_eq!(config.settings("meme").int("cooldown"), Ok(Some(30)));
+        assert_eq!(config.settings("hug").int("cooldown"), Ok(None));
+    }
+
+    #[test]
+    fn says_where_the_syntax_is_wrong() {
+        let (key, message) = error("prefix = \"!\"\nowners = [");
+        assert_eq!(key, "");
+        assert!(message.starts_with("Line 2, column"), "{}", message);
+    }
+
+    #[test]
+    fn names_the_key_that_is_wrong() {
+        assert_eq!(error("tokne = \"x\""), error_at("tokne", "unknown key"));
+        assert_eq!(error("token = \"a\"\ntoken_file = \"b\""),
+                   error_at("token_file",
+                            "only one of `token` and `token_file` may be set"));
+        assert_eq!(error("prefix = \" \""),
+                   error_at("prefix", "must not be empty"));
+        assert_eq!(error("prefix = 1"),
+                   error_at("prefix", "expected a string"));
+        assert_eq!(error("owners = [-1]"),
+                   error_at("owners", "expected a list of user ids"));
+        assert_eq!(error("[logging]\nevents = \"yes\""),
+                   error_at("logging.events", "expected true or false"));
+        assert_eq!(error("[runtime]\nworkers = 0"),
+                   error_at("runtime.workers", "must be at least 1"));
+        assert_eq!(error("[runtime]\nwhen_full = \"block\""),
+                   error_at("runtime.when_full",
+                            "expected \"busy\" or \"drop\""));
+    }
+
+    #[test]
+    fn checks_the_plugins() {
+        assert_eq!(error("[plugins]\nenabled = [\"nope\"]"),
+                   error_at("plugins.enabled",
+                            "there is no plugin called `nope`"));
+        assert_eq!(error("[plugins]\nenabled = [\"hug\", \"hug\"]"),
+                   error_at("plugins.enabled", "`hug` is listed twice"));
+        assert_eq!(error("[plugins.nope]\nkey = 1"),
+                   error_at("plugins.nope", "there is no such plugin"));
+        assert_eq!(error("[plugins]\nmeme = 1"),
+                   error_at("plugins.meme", "expected a table"));
+    }
+
+    #[test]
+    fn checks_the_settings_of_plugins_that_are_not_enabled() {
+        let text = "[plugins]\nenabled = [\"hug\"]\n\
+                    [plugins.meme]\ncolldown = 30\n";
+        assert_eq!(error(text),
+                   error_at("plugins.meme.colldown", "unknown key"));
+        assert_eq!(error("[plugins.hug]\nhugs = 2"),
+                   error_at("plugins.hug.hugs", "unknown key"));
+        let text = "[plugins]\nenabled = [\"hug\"]\n\
+                    [plugins.anime]\nquality = \"1080p\"\n";
+        assert!(Config::parse(text).is_ok());
+    }
+}
@@ -0,0 +1,215 @@
+use std::io::{self, BufRead};
+use discord::Error;
+use discord::model::{Message as DiscordMessage, ChannelId, MessageId, ServerId,
+                     UserId, ReadyEvent, ReactionEmoji, Permissions};
+use bot::{Bot, Message};
+use embed::Embed;
+use transport::{Transport, EventStream, fake_message, fake_user};
+
+/// Who the lines typed on stdin appear to come from.
+pub struct ConsoleOptions {
+    pub author: String,
+    pub channel: u64,
+    pub server: u64
+}
+
+impl ConsoleOptions {
+    /// Reads `--author NAME`, `--channel ID` and `--server ID` from the
+    /// command line, falling back to defaults for anything missing. Fails
+    /// on an id that is not a number.
+    pub fn from_args(args: &[String]) -> Result<ConsoleOptions, String> {
+        let mut opts = ConsoleOptions {
+            author: "console".to_string(),
+            channel: 1,
+            server: 1
+        };
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--author" => if let Some(v) = iter.next() {
+                    opts.author = v.clone();
+                },
+                "--channel" => if let Some(v) = iter.next() {
+                    opts.channel = v.parse()
+                        .map_err(|_| format!("Invalid channel id {}", v))?;
+                },
+                "--server" => if let Some(v) = iter.next() {
+                    opts.server = v.parse()
+                        .map_err(|_| format!("Invalid server id {}", v))?;
+                },
+                _ => {}
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// A `Transport` that prints outgoing messages to stdout.
+pub struct ConsoleTransport;
+
+impl Transport for ConsoleTransport {
+    fn connect(&self) -> Result<(Box<EventStream>, ReadyEvent), Error> {
+        Err(Error::Other("The console has no gateway"))
+    }
+
+    fn send_message(&self, channel: ChannelId, text: &str)
+        -> Result<DiscordMessage, Error> {
+        println!("[bot] {}", text);
+        Ok(fake_message(channel, fake_user(0, "bot"), text))
+    }
+
+    fn send_embed(&self, channel: ChannelId, text: &str, embed: &Embed)
+        -> Result<DiscordMessage, Error> {
+        if !text.is_empty() {
+            println!("[bot] {}", text);
+        }
+        let parts = [("title", &embed.title), ("url", &embed.url),
+                     ("description", &embed.description),
+                     ("thumbnail", &embed.thumbnail), ("image", &embed.image),
+                     ("footer", &embed.footer),
+                     ("timestamp", &embed.timestamp)];
+        for &(name, value) in parts.iter() {
+            if let Some(ref value) = *value {
+                println!("[embed] {}: {}", name, value);
+            }
+        }
+        for field in &embed.fields {
+            println!("[embed] {}: {}", field.name, field.value);
+        }
+        Ok(fake_message(channel, fake_user(0, "bot"), text))
+    }
+
+    fn send_file(&self, channel: ChannelId, text: &str, file: &[u8],
+                 filename: &str) -> Result<DiscordMessage, Error> {
+        if !text.is_empty() {
+            println!("[bot] {}", text);
+        }
+        println!("[file] {} ({} bytes)", filename, file.len());
+        Ok(fake_message(channel, fake_user(0, "bot"), text))
+    }
+
+    fn edit_message(&self, channel: ChannelId, message: MessageId, text: &str)
+        -> Result<DiscordMessage, Error> {
+        println!("[edit {}] {}", message.0, text);
+        let mut edited = fake_message(channel, fake_user(0, "bot"), text);
+        edited.id = message;
+        Ok(edited)
+    }
+
+    fn delete_message(&self, _channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        println!("[delete {}]", message.0);
+        Ok(())
+    }
+
+    fn add_reaction(&self, _channel: ChannelId, message: MessageId,
+                    emoji: &ReactionEmoji) -> Result<(), Error> {
+        println!("[react {}] {:?}", message.0, emoji);
+        Ok(())
+    }
+
+    fn remove_reaction(&self, _channel: ChannelId, message: MessageId,
+                       _user: Option<UserId>, emoji: &ReactionEmoji)
+        -> Result<(), Error> {
+        println!("[unreact {}] {:?}", message.0, emoji);
+        Ok(())
+    }
+
+    fn pin_message(&self, _channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        println!("[pin {}]", message.0);
+        Ok(())
+    }
+
+    fn unpin_message(&self, _channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        println!("[unpin {}]", message.0);
+        Ok(())
+    }
+
+    fn broadcast_typing(&self, _channel: ChannelId) -> Result<(), Error> {
+        println!("[bot is typing]");
+        Ok(())
+    }
+}
+
+/// Dispatches every line read from stdin to the bot's plugins as a message
+/// from the configured author, waiting for the handlers before reading the
+/// next line. Shuts the plugins down at end of input.
+pub fn run(bot: &Bot, opts: &ConsoleOptions) {
+    let stdin = io::stdin();
+    feed(bot, opts, stdin.lock());
+    bot.shutdown();
+}
+
+/// Dispatches each line of `input` as `run` does, without shutting down.
+fn feed<R: BufRead>(bot: &Bot, opts: &ConsoleOptions, input: R) {
+    let author = fake_user(1, &opts.author);
+    for line in input.lines() {
+        let line = line.expect("Failed to read stdin");
+        if line.is_empty() { continue }
+
+        let mut msg = Message::with_server(
+            fake_message(ChannelId(opts.channel), author.clone(), &line),
+            Some(ServerId(opts.server)));
+        // Whoever is at the console owns the server, and the bot.
+        msg.set_permissions(Some(Permissions::all()));
+        msg.set_server_owner(true);
+        msg.set_owner(true);
+        bot.dispatch(msg);
+        bot.wait_idle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use discord::model::ChannelId;
+    use bot::Bot;
+    use transport::FakeTransport;
+    use super::{ConsoleOptions, feed};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn options_fall_back_to_defaults() {
+        let opts = ConsoleOptions::from_args(&args(&["bot", "--console"]))
+            .unwrap();
+        assert_eq!((opts.author.as_str(), opts.channel, opts.server),
+                   ("console", 1, 1));
+
+        let opts = ConsoleOptions::from_args(&args(&[
+            "bot", "--console", "--author", "miko", "--channel", "5",
+            "--server", "9"])).unwrap();
+        assert_eq!((opts.author.as_str(), opts.channel, opts.server),
+                   ("miko", 5, 9));
+    }
+
+    #[test]
+    fn a_bad_id_is_an_error() {
+        assert_eq!(ConsoleOptions::from_args(&args(&["--channel", "general"]))
+                   .err(), Some("Invalid channel id general".to_string()));
+        assert_eq!(ConsoleOptions::from_args(&args(&["--server", "-1"]))
+                   .err(), Some("Invalid server id -1".to_string()));
+    }
+
+    #[test]
+    fn lines_are_dispatched_as_the_server_owner() {
+        let fake = Arc::new(FakeTransport::new());
+        let bot = Bot::with_transport(fake.clone());
+        let opts = ConsoleOptions::from_args(&args(&["--channel", "5"]))
+            .unwrap();
+        // `!access` needs Manage Server, which the console has.
+        feed(&bot, &opts, Cursor::new("!prefix\n\n!access help\n"));
+
+        let sent = fake.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], (ChannelId(5),
+                             "<@1> Commands here start with `!`".to_string()));
+        assert_eq!(sent[1].1, "<@1> `help` has its default access");
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, UTC};
+use discord::builders::{EmbedBuilder, EmbedFieldsBuilder};
+
+/// A rich embed to send with `Connection::send_embed`. Every part is
+/// optional; set the ones you need with the builder methods.
+#[derive(Clone, Debug, Default)]
+pub struct Embed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+    pub colour: Option<u64>,
+    pub fields: Vec<EmbedField>,
+    pub thumbnail: Option<String>,
+    pub image: Option<String>,
+    pub footer: Option<String>,
+    /// An RFC 3339 timestamp.
+    pub timestamp: Option<String>
+}
+
+#[derive(Clone, Debug)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+    pub inline: bool
+}
+
+impl Embed {
+    pub fn new() -> Embed {
+        Embed::default()
+    }
+
+    pub fn title(mut self, title: &str) -> Embed {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Embed {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// The URL the title links to.
+    pub fn url(mut self, url: &str) -> Embed {
+        self.url = Some(url.to_string());
+        self
+    }
+
+    /// The colour of the bar on the left, as `0xRRGGBB`.
+    pub fn colour(mut self, colour: u64) -> Embed {
+        self.colour = Some(colour);
+        self
+    }
+
+    /// Adds a field. Inline fields are laid out side by side.
+    pub fn field(mut self, name: &str, value: &str, inline: bool) -> Embed {
+        self.fields.push(EmbedField {
+            name: name.to_string(),
+            value: value.to_string(),
+            inline: inline
+        });
+        self
+    }
+
+    /// The URL of a small image shown in the top right corner.
+    pub fn thumbnail(mut self, url: &str) -> Embed {
+        self.thumbnail = Some(url.to_string());
+        self
+    }
+
+    /// The URL of a large image shown below the description.
+    pub fn image(mut self, url: &str) -> Embed {
+        self.image = Some(url.to_string());
+        self
+    }
+
+    pub fn footer(mut self, text: &str) -> Embed {
+        self.footer = Some(text.to_string());
+        self
+    }
+
+    pub fn timestamp(mut self, time: DateTime<UTC>) -> Embed {
+        self.timestamp = Some(time.to_rfc3339());
+        self
+    }
+
+    /// Fills in a Discord `EmbedBuilder`.
+    pub fn build(&self, builder: EmbedBuilder) -> EmbedBuilder {
+        let mut b = builder;
+        if let Some(ref title) = self.title { b = b.title(title) }
+        if let Some(ref description) = self.description {
+            b = b.description(description)
+        }
+        if let Some(ref url) = self.url { b = b.url(url) }
+        if let Some(colour) = self.colour { b = b.color(colour) }
+        if !self.fields.is_empty() {
+            b = b.fields(|f| self.build_fields(f))
+        }
+        if let Some(ref url) = self.thumbnail { b = b.thumbnail(url) }
+        if let Some(ref url) = self.image { b = b.image(url) }
+        if let Some(ref text) = self.footer { b = b.footer(|f| f.text(text)) }
+        if let Some(ref time) = self.timestamp { b = b.timestamp(time) }
+        b
+    }
+
+    fn build_fields(&self, builder: EmbedFieldsBuilder) -> EmbedFieldsBuilder {
+        self.fields.iter().fold(builder, |f, field| {
+            f.field(&field.name, &field.value, field.inline)
+        })
+    }
+}
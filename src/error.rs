@@ -0,0 +1,86 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use discord::Error as DiscordError;
+
+/// The ways talking to Discord through a `Connection`, or handling a message,
+/// can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The bot is not allowed to do this in the channel.
+    PermissionDenied,
+    /// The message has this many characters, more than Discord allows.
+    TooLong(usize),
+    /// Discord asked to wait this many milliseconds before trying again.
+    RateLimited(u64),
+    /// The channel or message does not exist.
+    NotFound,
+    /// The file has this many bytes, more than Discord allows.
+    FileTooLarge(u64),
+    /// A file to upload could not be read.
+    Io(io::Error),
+    /// Any other failure talking to Discord.
+    Transport(DiscordError),
+    /// A plugin failed at something of its own, such as a search or saving
+    /// its data.
+    Plugin(String)
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl From<DiscordError> for Error {
+    fn from(err: DiscordError) -> Error {
+        let status = match err {
+            DiscordError::RateLimited(ms) => return Error::RateLimited(ms),
+            DiscordError::Status(ref status, _) => status.to_u16(),
+            _ => 0
+        };
+        match status {
+            403 => Error::PermissionDenied,
+            404 => Error::NotFound,
+            _ => Error::Transport(err)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TooLong(len) => {
+                write!(f, "Message is too long ({} characters)", len)
+            }
+            Error::RateLimited(ms) => {
+                write!(f, "Rate limited, retry after {}ms", ms)
+            }
+            Error::FileTooLarge(size) => {
+                write!(f, "File is too large ({} bytes, the limit is {})",
+                       size, ::bot::MAX_FILE_SIZE)
+            }
+            Error::Io(ref err) => write!(f, "Could not read file: {}", err),
+            Error::Transport(ref err) => write!(f, "Transport error: {:?}", err),
+            Error::Plugin(ref err) => f.write_str(err),
+            _ => f.write_str(self.description())
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::PermissionDenied => "Permission denied",
+            Error::TooLong(_) => "Message is too long",
+            Error::RateLimited(_) => "Rate limited",
+            Error::NotFound => "Not found",
+            Error::FileTooLarge(_) => "File is too large",
+            Error::Io(_) => "Could not read file",
+            Error::Transport(_) => "Transport error",
+            Error::Plugin(_) => "Plugin error"
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use discord::model::{Event, PossibleServer};
+use bot::{Connection, Message};
+use limit::Limiter;
+use middleware::Pipeline;
+use plugin::{self, Events, Plugin};
+use pool::WorkerPool;
+use typing::Typing;
+
+/// What the bot does with a matched message when the plugin's queue is
+/// full. It is never waited on, so that the gateway keeps being read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FullPolicy {
+    /// Drop the message.
+    Drop,
+    /// Drop the message and let the user know the bot is busy.
+    Busy
+}
+
+/// A snapshot of a plugin's task queue.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueStats {
+    pub queued: usize,
+    pub handled: usize,
+    pub rejected: usize,
+    pub busy: bool
+}
+
+/// Work queued for a plugin. Messages are handled through the middleware
+/// they were dispatched with.
+pub enum Task {
+    Message(Message, Arc<Pipeline>),
+    Event(Arc<Event>),
+    Tick
+}
+
+struct Queue {
+    tasks: VecDeque<Task>,
+    // How many of `tasks` are gateway events.
+    events: usize,
+    // Whether a drain job is queued or running on the pool.
+    scheduled: bool,
+    running_since: Option<Instant>,
+    reported: bool,
+    next_tick: Option<Instant>,
+    handled: usize,
+    rejected: usize
+}
+
+impl Queue {
+    /// How many tasks of the same sort as `task` are waiting: events, or
+    /// everything else.
+    fn waiting(&self, task: &Task) -> usize {
+        match *task {
+            Task::Event(_) => self.events,
+            _ => self.tasks.len() - self.events
+        }
+    }
+}
+
+/// A `PluginExecutor` owns a registered plugin and its message queue.
+/// Messages are handled one at a time in the order they were queued, on
+/// whichever pool worker is free, so a slow plugin only delays itself.
+#[derive(Clone)]
+pub struct PluginExecutor {
+    plugin: Arc<Box<Plugin>>,
+    queue: Arc<Mutex<Queue>>,
+    limiter: Arc<Limiter>,
+    tick_interval: Option<Duration>,
+    events: Events,
+    priority: i32
+}
+
+impl PluginExecutor {
+    pub fn new(plugin: Box<Plugin>) -> PluginExecutor {
+        let tick_interval = plugin.tick_interval();
+        let events = plugin.events();
+        let priority = plugin.priority();
+        PluginExecutor {
+            priority: priority,
+            plugin: Arc::new(plugin),
+            tick_interval: tick_interval,
+            events: events,
+            limiter: Arc::new(Limiter::new()),
+            queue: Arc::new(Mutex::new(Queue {
+                tasks: VecDeque::new(),
+                events: 0,
+                scheduled: false,
+                running_since: None,
+                reported: false,
+                next_tick: tick_interval.map(|i| Instant::now() + i),
+                handled: 0,
+                rejected: 0
+            }))
+        }
+    }
+
+    pub fn plugin(&self) -> &Plugin {
+        &**self.plugin
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Whether the plugin asked for events of this kind.
+    pub fn wants(&self, kind: Events) -> bool {
+        self.events.contains(kind)
+    }
+
+    /// The buckets counting messages against the plugin's rate limit.
+    pub fn limiter(&self) -> &Limiter {
+        &self.limiter
+    }
+
+    /// Queues a task for the plugin, starting a drain job on the pool if
+    /// none is running. Events and other tasks are counted apart, so that
+    /// events can never take the room of messages. Never waits: returns
+    /// `false` if the queue already holds `capacity` tasks of the same sort.
+    pub fn enqueue(&self, task: Task, conn: &Connection, pool: &WorkerPool,
+                   capacity: usize) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.waiting(&task) >= capacity {
+            queue.rejected += 1;
+            return false
+        }
+        if let Task::Event(_) = task {
+            queue.events += 1;
+        }
+        queue.tasks.push_back(task);
+
+        if !queue.scheduled {
+            queue.scheduled = true;
+            drop(queue);
+            let executor = self.clone();
+            let conn_1 = conn.clone();
+            pool.execute(move || executor.drain(&conn_1));
+        }
+        true
+    }
+
+    /// Returns whether the plugin's next tick is due, scheduling the one
+    /// after it if so.
+    pub fn tick_due(&self) -> bool {
+        let interval = match self.tick_interval {
+            Some(i) => i,
+            None => return false
+        };
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        match queue.next_tick {
+            Some(next) if next <= now => {
+                queue.next_tick = Some(now + interval);
+                true
+            }
+            _ => false
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let queue = self.queue.lock().unwrap();
+        QueueStats {
+            queued: queue.tasks.len(),
+            handled: queue.handled,
+            rejected: queue.rejected,
+            busy: queue.running_since.is_some()
+        }
+    }
+
+    /// Reports the handler if it has been running for longer than
+    /// `timeout`. Each invocation is only reported once.
+    pub fn check_timeout(&self, timeout: Duration) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.reported { return }
+        if let Some(since) = queue.running_since {
+            if since.elapsed() > timeout {
+                queue.reported = true;
+                println!("[Warning] {} has been handling a message for over {}s \
+                          ({} queued)",
+                         self.plugin.name(), timeout.as_secs(),
+                         queue.tasks.len());
+            }
+        }
+    }
+
+    fn drain(&self, conn: &Connection) {
+        loop {
+            let task = {
+                let mut queue = self.queue.lock().unwrap();
+                match queue.tasks.pop_front() {
+                    Some(task) => {
+                        if let Task::Event(_) = task {
+                            queue.events -= 1;
+                        }
+                        queue.running_since = Some(Instant::now());
+                        queue.reported = false;
+                        task
+                    }
+                    None => {
+                        queue.scheduled = false;
+                        return
+                    }
+                }
+            };
+
+            // Catch panics here rather than in the pool so that `scheduled`
+            // is always reset and the queue keeps draining.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                match task {
+                    Task::Message(ref msg, ref pipeline) => {
+                        // Dropped when the handler returns or unwinds.
+                        let _typing = if self.plugin.typing() {
+                            Some(Typing::start(conn, msg.channel_id()))
+                        } else {
+                            None
+                        };
+                        pipeline.run(&**self.plugin, msg, conn);
+                    }
+                    Task::Event(ref event) => {
+                        dispatch_event(&**self.plugin, event, conn)
+                    }
+                    Task::Tick => self.plugin.on_tick(conn)
+                }
+            }));
+            if result.is_err() {
+                println!("[Error] {} panicked while handling a task",
+                         self.plugin.name());
+            }
+
+            let mut queue = self.queue.lock().unwrap();
+            if queue.reported {
+                if let Some(since) = queue.running_since {
+                    let elapsed = since.elapsed();
+                    println!("[Warning] {} finished after {}.{:03}s",
+                             self.plugin.name(), elapsed.as_secs(),
+                             elapsed.subsec_nanos() / 1_000_000);
+                }
+            }
+            queue.running_since = None;
+            queue.handled += 1;
+        }
+    }
+}
+
+/// Adds a plugin after every one of the same or a higher priority, keeping
+/// `plugins` in the order messages are matched in.
+pub fn insert(plugins: &mut Vec<PluginExecutor>, executor: PluginExecutor) {
+    let i = plugins.iter()
+        .position(|e| e.priority < executor.priority)
+        .unwrap_or(plugins.len());
+    plugins.insert(i, executor);
+}
+
+/// Returns which of the `Plugin::events` the event is, or `None` if
+/// plugins have no hook for it.
+pub fn event_kind(event: &Event) -> Option<Events> {
+    match *event {
+        Event::MessageUpdate { .. } => Some(plugin::MESSAGE_EDITS),
+        Event::MessageDelete { .. } => Some(plugin::MESSAGE_DELETES),
+        Event::ReactionAdd(_) |
+        Event::ReactionRemove(_) => Some(plugin::REACTIONS),
+        Event::ServerMemberAdd(..) |
+        Event::ServerMemberRemove(..) => Some(plugin::MEMBERS),
+        Event::PresenceUpdate { .. } => Some(plugin::PRESENCES),
+        Event::ServerCreate(PossibleServer::Online(_)) => {
+            Some(plugin::SERVERS)
+        }
+        _ => None
+    }
+}
+
+fn dispatch_event(plugin: &Plugin, event: &Event, conn: &Connection) {
+    match *event {
+        Event::MessageUpdate { id, channel_id, ref content, .. } => {
+            plugin.on_message_edit(channel_id, id,
+                                   content.as_ref().map(|c| c.as_str()), conn)
+        }
+        Event::MessageDelete { channel_id, message_id } => {
+            plugin.on_message_delete(channel_id, message_id, conn)
+        }
+        Event::ReactionAdd(ref reaction) => plugin.on_reaction_add(reaction, conn),
+        Event::ReactionRemove(ref reaction) => {
+            plugin.on_reaction_remove(reaction, conn)
+        }
+        Event::ServerMemberAdd(server_id, ref member) => {
+            plugin.on_member_join(server_id, member, conn)
+        }
+        Event::ServerMemberRemove(server_id, ref user) => {
+            plugin.on_member_leave(server_id, user, conn)
+        }
+        Event::PresenceUpdate { server_id, ref presence, .. } => {
+            plugin.on_presence_update(server_id, presence, conn)
+        }
+        Event::ServerCreate(PossibleServer::Online(ref server)) => {
+            plugin.on_server_create(server, conn)
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use discord::model::{ChannelId, Event, MessageId};
+    use bot::{Connection, Message};
+    use error::Result;
+    use middleware::Pipeline;
+    use plugin::Plugin;
+    use pool::WorkerPool;
+    use transport::{test_connection, test_message};
+    use super::{insert, PluginExecutor, Task};
+
+    /// Records what it handled, and panics on "panic".
+    struct Recorder {
+        seen: Arc<Mutex<Vec<String>>>,
+        tick: Option<Duration>
+    }
+
+    impl Plugin for Recorder {
+        fn new() -> Box<Plugin> {
+            Box::new(Recorder { seen: Arc::new(Mutex::new(Vec::new())),
+                                tick: None })
+        }
+
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn is_match(&self, _msg: &Message) -> bool {
+            true
+        }
+
+        fn tick_interval(&self) -> Option<Duration> {
+            self.tick
+        }
+
+        fn handle(&self, msg: &Message, _conn: &Connection) -> Result<()> {
+            if msg.content() == "panic" { panic!("asked to") }
+            self.seen.lock().unwrap().push(msg.content());
+            Ok(())
+        }
+    }
+
+    fn recorder(tick: Option<Duration>)
+        -> (PluginExecutor, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let plugin = Recorder { seen: seen.clone(), tick: tick };
+        (PluginExecutor::new(Box::new(plugin)), seen)
+    }
+
+    /// Matches nothing, and is only ordered.
+    struct Ranked {
+        name: &'static str,
+        priority: i32
+    }
+
+    impl Plugin for Ranked {
+        fn new() -> Box<Plugin> {
+            Box::new(Ranked { name: "ranked", priority: 0 })
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn is_match(&self, _msg: &Message) -> bool {
+            false
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+
+        fn handle(&self, _msg: &Message, _conn: &Connection) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn message(content: &str) -> Task {
+        Task::Message(test_message(content), Arc::new(Pipeline::new()))
+    }
+
+    #[test]
+    fn handles_messages_in_the_order_they_were_queued() {
+        let (e, seen) = recorder(None);
+        let (_, conn) = test_connection();
+        let pool = WorkerPool::new(4);
+        let sent = (0..50).map(|i| i.to_string()).collect::<Vec<String>>();
+        for text in &sent {
+            assert!(e.enqueue(message(text), &conn, &pool, 100));
+        }
+        pool.wait_idle();
+        assert_eq!(*seen.lock().unwrap(), sent);
+        assert_eq!(e.stats().handled, 50);
+    }
+
+    #[test]
+    fn rejects_tasks_past_capacity() {
+        let (e, _) = recorder(None);
+        let (_, conn) = test_connection();
+        // Without workers nothing is taken off the queue.
+        let pool = WorkerPool::new(0);
+        assert!(e.enqueue(message("a"), &conn, &pool, 2));
+        assert!(e.enqueue(message("b"), &conn, &pool, 2));
+        assert!(!e.enqueue(message("c"), &conn, &pool, 2));
+
+        // Events have room of their own.
+        let event = Arc::new(Event::MessageDelete {
+            channel_id: ChannelId(1),
+            message_id: MessageId(1)
+        });
+        assert!(e.enqueue(Task::Event(event), &conn, &pool, 2));
+
+        let stats = e.stats();
+        assert_eq!(stats.queued, 3);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(pool.stats().queued, 1);
+    }
+
+    #[test]
+    fn a_panicking_handler_does_not_stop_the_queue() {
+        let (e, seen) = recorder(None);
+        let (_, conn) = test_connection();
+        let pool = WorkerPool::new(1);
+        e.enqueue(message("panic"), &conn, &pool, 10);
+        e.enqueue(message("after"), &conn, &pool, 10);
+        pool.wait_idle();
+        assert_eq!(*seen.lock().unwrap(), vec!["after"]);
+        assert_eq!(e.stats().handled, 2);
+        assert!(!e.stats().busy);
+
+        // The drain job was not left marked as scheduled.
+        e.enqueue(message("later"), &conn, &pool, 10);
+        pool.wait_idle();
+        assert_eq!(*seen.lock().unwrap(), vec!["after", "later"]);
+    }
+
+    #[test]
+    fn ticks_only_when_due() {
+        let (never, _) = recorder(None);
+        assert!(!never.tick_due());
+
+        let (every, _) = recorder(Some(Duration::from_secs(0)));
+        assert!(every.tick_due());
+        let (hourly, _) = recorder(Some(Duration::from_secs(3600)));
+        assert!(!hourly.tick_due());
+    }
+
+    #[test]
+    fn plugins_are_ordered_by_priority_then_registration() {
+        let mut plugins = Vec::new();
+        for &(name, priority) in &[("a", 0), ("b", 5), ("c", 0), ("d", 5),
+                                   ("e", -1), ("f", 10)] {
+            let plugin = Ranked { name: name, priority: priority };
+            insert(&mut plugins, PluginExecutor::new(Box::new(plugin)));
+        }
+        let names = plugins.iter().map(|e| e.plugin().name())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["f", "b", "d", "a", "c", "e"]);
+    }
+}
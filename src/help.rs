@@ -0,0 +1,152 @@
+use std::sync::{Arc, RwLock, Weak};
+use access::AccessRules;
+use bot::{Connection, Message};
+use command::{Args, ArgKind, Command, Router};
+use error::Result;
+use executor::PluginExecutor;
+use plugin::Plugin;
+use toggle::Toggles;
+
+/// The built-in `!help` command. Every `Bot` registers one, which lists the
+/// other plugins from the `description` and `commands` they declare. Plugins
+/// that are turned off where it is asked are left out.
+pub struct HelpPlugin {
+    plugins: Weak<RwLock<Vec<PluginExecutor>>>,
+    access: Arc<AccessRules>,
+    toggles: Arc<Toggles>,
+    commands: Router<HelpPlugin>
+}
+
+impl HelpPlugin {
+    pub fn with_plugins(plugins: Weak<RwLock<Vec<PluginExecutor>>>,
+                        access: Arc<AccessRules>, toggles: Arc<Toggles>)
+        -> HelpPlugin {
+        HelpPlugin {
+            plugins: plugins,
+            access: access,
+            toggles: toggles,
+            commands: Router::new()
+                .add(Command::new("help")
+                     .optional("command", ArgKind::Word)
+                     .description("Lists commands, or shows how to use one"),
+                     HelpPlugin::help)
+        }
+    }
+
+    fn help(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let plugins = match self.plugins.upgrade() {
+            Some(plugins) => plugins,
+            None => return Ok(())
+        };
+        let plugins = plugins.read().unwrap();
+        let plugins = plugins.iter().map(|e| e.plugin())
+            .filter(|p| self.toggles.is_enabled(msg, p.name()));
+        match args.str("command") {
+            None => {
+                let text = overview(plugins, &self.access, msg);
+                conn.reply_split(msg, &text, None)
+            }
+            Some(name) => {
+                let name = name.trim_left_matches(msg.prefix());
+                let text = details(plugins, &self.access, name, msg);
+                if text.is_empty() {
+                    conn.reply(msg, &format!("There is no `{}{}` command",
+                                             msg.prefix(), name))?;
+                    Ok(())
+                } else {
+                    conn.reply_split(msg, &text, None)
+                }
+            }
+        }
+    }
+}
+
+impl Plugin for HelpPlugin {
+    /// Creates a `HelpPlugin` that knows of no other plugins. Use the one
+    /// the `Bot` registers instead.
+    fn new() -> Box<Plugin> {
+        Box::new(HelpPlugin::with_plugins(Weak::new(),
+                                          Arc::new(AccessRules::load()),
+                                          Arc::new(Toggles::load())))
+    }
+
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn description(&self) -> &'static str {
+        "Explains the other commands"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    fn is_match(&self, msg: &Message) -> bool {
+        self.commands.is_match(msg)
+    }
+
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+}
+
+/// Lists every plugin with the commands the author of `msg` may use. A
+/// plugin whose commands are all off limits is left out.
+fn overview<'a, I>(plugins: I, access: &AccessRules, msg: &Message) -> String
+    where I: Iterator<Item = &'a Plugin> {
+    let mut text = String::new();
+    for plugin in plugins {
+        let all = plugin.commands();
+        let usable = all.iter()
+            .filter(|c| may_use(plugin, c, access, msg))
+            .collect::<Vec<_>>();
+        if usable.is_empty() && !all.is_empty() { continue }
+
+        text.push_str(&format!("**{}**", plugin.name()));
+        if !plugin.description().is_empty() {
+            text.push_str(&format!(" — {}", plugin.description()));
+        }
+        text.push('\n');
+        for command in usable {
+            text.push_str(&format!("`{}` — {}\n", command.usage(msg.prefix()),
+                                   command.description));
+        }
+    }
+    text.push_str(&format!("Use `{}help <command>` for details.",
+                           msg.prefix()));
+    text
+}
+
+/// Describes every command called `name` that the author of `msg` may use,
+/// or returns an empty string if there are none.
+fn details<'a, I>(plugins: I, access: &AccessRules, name: &str,
+                  msg: &Message) -> String
+    where I: Iterator<Item = &'a Plugin> {
+    let mut text = String::new();
+    for plugin in plugins {
+        for command in plugin.commands() {
+            if !command.is_named(name) ||
+                !may_use(plugin, &command, access, msg) {
+                continue
+            }
+            text.push_str(&format!("`{}` — {}\n", command.usage(msg.prefix()),
+                                   command.description));
+            if !command.aliases.is_empty() {
+                let aliases = command.aliases.iter()
+                    .map(|a| format!("`{}{}`", msg.prefix(), a))
+                    .collect::<Vec<_>>();
+                text.push_str(&format!("Also: {}\n", aliases.join(", ")));
+            }
+        }
+    }
+    text
+}
+
+/// Whether the author of `msg` may use `command`, both as far as the plugin
+/// and the access rules are concerned.
+fn may_use(plugin: &Plugin, command: &Command, access: &AccessRules,
+           msg: &Message) -> bool {
+    plugin.can_use(command, msg) && access.allows(plugin, Some(command), msg)
+}
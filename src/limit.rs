@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use bot::Message;
+use plugin;
+
+/// How often buckets that have run out are dropped.
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+/// What a rate limit counts messages by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    User,
+    Channel,
+    /// The server, or the channel for private messages.
+    Server
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Holds up to `capacity` messages, and gets one more back every
+    /// `refill`. Allows short bursts.
+    TokenBucket { capacity: u32, refill: Duration },
+    /// Allows `max` messages in each `window`, counted from the first.
+    FixedWindow { max: u32, window: Duration }
+}
+
+/// How often a plugin's command may be used, as returned by
+/// `Plugin::rate_limit`.
+/// Messages over the limit are not handled, and the author is told once to
+/// slow down. The bot's owners and members with an exempt role are never
+/// limited.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub scope: Scope,
+    pub strategy: Strategy,
+    /// Role names that are not limited.
+    pub exempt: Vec<String>
+}
+
+impl RateLimit {
+    /// `capacity` messages at once per user, then one every `refill`.
+    pub fn token_bucket(capacity: u32, refill: Duration) -> RateLimit {
+        RateLimit::with_strategy(Strategy::TokenBucket {
+            capacity: capacity,
+            refill: refill
+        })
+    }
+
+    /// `max` messages per user in each `window`.
+    pub fn fixed_window(max: u32, window: Duration) -> RateLimit {
+        RateLimit::with_strategy(Strategy::FixedWindow {
+            max: max,
+            window: window
+        })
+    }
+
+    fn with_strategy(strategy: Strategy) -> RateLimit {
+        RateLimit {
+            scope: Scope::User,
+            strategy: strategy,
+            exempt: Vec::new()
+        }
+    }
+
+    /// Counts messages by `scope` instead of by user.
+    pub fn per(mut self, scope: Scope) -> RateLimit {
+        self.scope = scope;
+        self
+    }
+
+    /// Does not limit members with the role called `role`.
+    pub fn exempt(mut self, role: &str) -> RateLimit {
+        self.exempt.push(role.to_string());
+        self
+    }
+
+    fn is_exempt(&self, msg: &Message) -> bool {
+        msg.is_owner() || msg.roles().iter().any(|role| {
+            self.exempt.iter().any(|r| r.to_lowercase() ==
+                                   role.name.to_lowercase())
+        })
+    }
+
+    fn key(&self, command: Option<&'static str>, msg: &Message) -> Key {
+        let id = match self.scope {
+            Scope::User => msg.author().id.0,
+            Scope::Channel => msg.channel_id().0,
+            Scope::Server => {
+                msg.server_id().map_or(msg.channel_id().0, |s| s.0)
+            }
+        };
+        (command, self.scope, id)
+    }
+}
+
+/// Whether a message is within its plugin's rate limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit until `retry_after` has passed. `warn` is only set
+    /// for the first message over it.
+    Deny { retry_after: Duration, warn: bool }
+}
+
+/// What a bucket counts: the command, and the user, channel or server.
+type Key = (Option<&'static str>, Scope, u64);
+
+struct Bucket {
+    // The limit's strategy when the bucket was last used.
+    strategy: Strategy,
+    // Tokens left, or messages counted in the window.
+    level: f64,
+    // When tokens were last added, or the window started.
+    since: Instant,
+    warned: bool
+}
+
+impl Bucket {
+    /// Brings the bucket up to date, and returns whether it is as good as
+    /// new and can be dropped.
+    fn refresh(&mut self, now: Instant) -> bool {
+        let elapsed = secs(now - self.since);
+        match self.strategy {
+            Strategy::TokenBucket { capacity, refill } => {
+                let capacity = capacity as f64;
+                let refill = secs(refill);
+                self.level = if refill > 0.0 {
+                    (self.level + elapsed / refill).min(capacity)
+                } else {
+                    capacity
+                };
+                self.since = now;
+                self.level >= capacity
+            }
+            Strategy::FixedWindow { window, .. } => {
+                if elapsed >= secs(window) {
+                    self.level = 0.0;
+                    self.since = now;
+                }
+                self.level == 0.0
+            }
+        }
+    }
+
+    /// Takes one message out of the bucket, or returns how long until it
+    /// can.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.strategy {
+            Strategy::TokenBucket { refill, .. } => {
+                if self.level >= 1.0 {
+                    self.level -= 1.0;
+                    return Ok(())
+                }
+                Err(duration((1.0 - self.level) * secs(refill)))
+            }
+            Strategy::FixedWindow { max, window } => {
+                if self.level < max as f64 {
+                    self.level += 1.0;
+                    return Ok(())
+                }
+                Err(window - (now - self.since))
+            }
+        }
+    }
+}
+
+/// The buckets of one plugin's rate limits, apart for each command. Each
+/// `PluginExecutor` keeps one.
+pub struct Limiter {
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    pruned: Mutex<Instant>
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter {
+            buckets: Mutex::new(HashMap::new()),
+            pruned: Mutex::new(Instant::now())
+        }
+    }
+
+    /// Counts `msg`, which calls the command named `command`, against
+    /// `limit`.
+    pub fn check(&self, limit: &RateLimit, command: Option<&'static str>,
+                 msg: &Message) -> Verdict {
+        self.check_at(limit, command, msg, Instant::now())
+    }
+
+    fn check_at(&self, limit: &RateLimit, command: Option<&'static str>,
+                msg: &Message, now: Instant) -> Verdict {
+        if limit.is_exempt(msg) { return Verdict::Allow }
+        self.prune(now);
+
+        let mut buckets = plugin::lock(&self.buckets);
+        let key = limit.key(command, msg);
+        let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+            strategy: limit.strategy,
+            level: match limit.strategy {
+                Strategy::TokenBucket { capacity, .. } => capacity as f64,
+                Strategy::FixedWindow { .. } => 0.0
+            },
+            since: now,
+            warned: false
+        });
+        // The limit may have been reconfigured since.
+        bucket.strategy = limit.strategy;
+        bucket.refresh(now);
+        match bucket.take(now) {
+            Ok(()) => {
+                bucket.warned = false;
+                Verdict::Allow
+            }
+            Err(retry_after) => {
+                let warn = !bucket.warned;
+                bucket.warned = true;
+                Verdict::Deny {
+                    retry_after: retry_after,
+                    warn: warn
+                }
+            }
+        }
+    }
+
+    /// Drops the buckets that have run out, at most once per
+    /// `PRUNE_INTERVAL_SECS`.
+    fn prune(&self, now: Instant) {
+        let mut pruned = plugin::lock(&self.pruned);
+        if now - *pruned < Duration::from_secs(PRUNE_INTERVAL_SECS) { return }
+        *pruned = now;
+        let mut buckets = plugin::lock(&self.buckets);
+        let expired = buckets.iter_mut()
+            .filter_map(|(key, bucket)| {
+                if bucket.refresh(now) { Some(*key) } else { None }
+            })
+            .collect::<Vec<Key>>();
+        for key in expired {
+            buckets.remove(&key);
+        }
+    }
+}
+
+/// The reply to a message over its rate limit.
+pub fn slow_down(retry_after: Duration) -> String {
+    // Round up, so that "try again in 0s" is never said.
+    let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+    format!("whoa there, slow down desu senpai (try again in {}s)", secs)
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn duration(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use discord::model::ChannelId;
+    use bot::Message;
+    use transport::{fake_message, fake_role, fake_user};
+    use super::{Limiter, RateLimit, Scope, Verdict, PRUNE_INTERVAL_SECS};
+
+    fn message(channel: u64, user: u64) -> Message {
+        Message::new(fake_message(ChannelId(channel), fake_user(user, "user"),
+                                  "!a"))
+    }
+
+    fn deny(retry_after: u64, warn: bool) -> Verdict {
+        Verdict::Deny {
+            retry_after: Duration::from_secs(retry_after),
+            warn: warn
+        }
+    }
+
+    fn verdicts(limiter: &Limiter, limit: &RateLimit, msg: &Message,
+                at: Instant, times: usize) -> Vec<Verdict> {
+        (0..times).map(|_| limiter.check_at(limit, Some("a"), msg, at))
+            .collect()
+    }
+
+    #[test]
+    fn a_token_bucket_refills_over_time() {
+        let limiter = Limiter::new();
+        let limit = RateLimit::token_bucket(2, Duration::from_secs(10));
+        let msg = message(1, 1);
+        let start = Instant::now();
+        assert_eq!(verdicts(&limiter, &limit, &msg, start, 4),
+                   vec![Verdict::Allow, Verdict::Allow, deny(10, true),
+                        deny(10, false)]);
+        assert_eq!(verdicts(&limiter, &limit, &msg,
+                            start + Duration::from_secs(5), 1),
+                   vec![deny(5, false)]);
+        assert_eq!(verdicts(&limiter, &limit, &msg,
+                            start + Duration::from_secs(10), 2),
+                   vec![Verdict::Allow, deny(10, true)]);
+    }
+
+    #[test]
+    fn a_fixed_window_starts_over_when_it_ends() {
+        let limiter = Limiter::new();
+        let limit = RateLimit::fixed_window(2, Duration::from_secs(10));
+        let msg = message(1, 1);
+        let start = Instant::now();
+        assert_eq!(verdicts(&limiter, &limit, &msg, start, 3),
+                   vec![Verdict::Allow, Verdict::Allow, deny(10, true)]);
+        assert_eq!(verdicts(&limiter, &limit, &msg,
+                            start + Duration::from_secs(4), 1),
+                   vec![deny(6, false)]);
+        assert_eq!(verdicts(&limiter, &limit, &msg,
+                            start + Duration::from_secs(10), 3),
+                   vec![Verdict::Allow, Verdict::Allow, deny(10, true)]);
+    }
+
+    #[test]
+    fn limits_count_by_user_or_channel_and_command() {
+        let limiter = Limiter::new();
+        let limit = RateLimit::token_bucket(1, Duration::from_secs(60));
+        let now = Instant::now();
+        let check = |limit: &RateLimit, command, channel, user| {
+            limiter.check_at(limit, Some(command), &message(channel, user), now)
+                == Verdict::Allow
+        };
+        assert!(check(&limit, "a", 1, 1));
+        assert!(!check(&limit, "a", 2, 1));
+        assert!(check(&limit, "a", 1, 2));
+        assert!(check(&limit, "b", 1, 1));
+
+        let limit = limit.per(Scope::Channel);
+        assert!(check(&limit, "a", 1, 1));
+        assert!(!check(&limit, "a", 1, 2));
+        assert!(check(&limit, "a", 2, 1));
+    }
+
+    #[test]
+    fn owners_and_exempt_roles_are_not_limited() {
+        let limiter = Limiter::new();
+        let limit = RateLimit::token_bucket(1, Duration::from_secs(60))
+            .exempt("Mods");
+        let now = Instant::now();
+        let mut owner = message(1, 1);
+        owner.set_owner(true);
+        let mut moderator = message(1, 2);
+        moderator.set_roles(vec![fake_role(1, "mods")]);
+        let mut other = message(1, 3);
+        other.set_roles(vec![fake_role(2, "members")]);
+
+        assert_eq!(verdicts(&limiter, &limit, &owner, now, 3),
+                   vec![Verdict::Allow; 3]);
+        assert_eq!(verdicts(&limiter, &limit, &moderator, now, 3),
+                   vec![Verdict::Allow; 3]);
+        assert_eq!(verdicts(&limiter, &limit, &other, now, 2),
+                   vec![Verdict::Allow, deny(60, true)]);
+    }
+
+    #[test]
+    fn idle_buckets_are_dropped() {
+        let limiter = Limiter::new();
+        let quick = RateLimit::token_bucket(1, Duration::from_secs(1));
+        let slow = RateLimit::fixed_window(1, Duration::from_secs(600));
+        let start = Instant::now();
+        limiter.check_at(&quick, Some("a"), &message(1, 1), start);
+        limiter.check_at(&slow, Some("b"), &message(1, 2), start);
+
+        // Buckets are only looked at once in a while.
+        let soon = start + Duration::from_secs(30);
+        limiter.check_at(&quick, Some("a"), &message(1, 3), soon);
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 3);
+
+        // By then the first user's bucket is full again and the third
+        // user's is too, but the second user's window is still open.
+        let later = start + Duration::from_secs(PRUNE_INTERVAL_SECS + 1);
+        limiter.check_at(&quick, Some("a"), &message(1, 4), later);
+        let mut users = limiter.buckets.lock().unwrap().keys()
+            .map(|&(_, _, user)| user)
+            .collect::<Vec<u64>>();
+        users.sort();
+        assert_eq!(users, vec![2, 4]);
+    }
+}
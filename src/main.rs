@@ -1,27 +1,83 @@
 extern crate chrono;
 extern crate discord;
+extern crate rand;
 extern crate rustc_serialize;
+extern crate signal_hook;
+extern crate toml;
 
-use plugin::Plugin;
+use std::env;
+use std::process;
+use std::sync::Arc;
+
+use config::Source;
 
 mod plugins;
 mod bot;
+mod console;
+mod executor;
+mod help;
+mod outbox;
+mod pool;
+mod prefix;
+mod reload;
+mod split;
+mod store;
+mod toggle;
+mod typing;
+pub mod access;
+pub mod command;
+pub mod config;
+pub mod embed;
+pub mod error;
+pub mod limit;
+pub mod middleware;
 pub mod plugin;
+pub mod transport;
 
 fn main() {
-    let mut bot = bot::Bot::new();
-    let mut plugins: Vec<Box<Plugin>> = Vec::new();
-
-    plugins.push(plugins::bully::BullyPlugin::new());
-    plugins.push(plugins::bully::HugPlugin::new());
-    plugins.push(plugins::meme::MemePlugin::new());
-    plugins.push(plugins::fourchan::FourchanImagePlugin::new());
-    plugins.push(plugins::fourchan::FourchanPlugin::new());
-    plugins.push(plugins::anime::AnimePlugin::new());
+    let args: Vec<String> = env::args().collect();
+    let console = if args.iter().any(|a| a == "--console") {
+        match console::ConsoleOptions::from_args(&args) {
+            Ok(opts) => Some(opts),
+            Err(err) => {
+                println!("[Error] {}", err);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let source = match Source::from_args(&args) {
+        Ok(source) => source,
+        Err(err) => {
+            println!("[Error] {}", err);
+            process::exit(1);
+        }
+    };
+    let config = match source.load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("[Error] Invalid config: {}", err);
+            process::exit(1);
+        }
+    };
 
-    for p in plugins {
-        bot.register(p);
+    let mut bot = if console.is_some() {
+        bot::Bot::with_transport(Arc::new(console::ConsoleTransport))
+    } else {
+        match config.token {
+            Some(ref token) => bot::Bot::with_token(token),
+            None => bot::Bot::new()
+        }
+    };
+    if let Err(err) = bot.configure(source, config, plugins::build) {
+        println!("[Error] Invalid config: {}", err);
+        process::exit(1);
     }
 
-    bot.connect();
+    if let Some(opts) = console {
+        console::run(&bot, &opts);
+    } else if !bot.connect() {
+        process::exit(1);
+    }
 }
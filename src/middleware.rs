@@ -0,0 +1,247 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use bot::{Connection, Message};
+use error::Error;
+use plugin::Plugin;
+
+/// What a middleware wants done with a message before it is handled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Flow {
+    /// Goes on to the next middleware, and then the handler.
+    Continue,
+    /// Replies with the text instead of handling the message.
+    Reply(String),
+    /// Drops the message without a word.
+    Stop
+}
+
+/// How handling a message ended.
+#[derive(Debug)]
+pub enum Outcome<'a> {
+    Handled,
+    /// The handler returned an error.
+    Failed(&'a Error),
+    Panicked,
+    /// A middleware stopped the message before the handler.
+    Stopped
+}
+
+/// A `Middleware` wraps every call of `Plugin::handle`, for behaviour that
+/// would otherwise be copied into every plugin, such as logging or timing.
+/// Middleware runs on the worker handling the message, in the order it was
+/// added with `Bot::add_middleware`. Every `Bot` starts with `Log`.
+///
+/// `before` is called in order until one middleware returns something other
+/// than `Flow::Continue`, and the handler is only called if none does.
+/// `after` is then called in reverse on every middleware whose `before` was
+/// called, with how long it all took.
+pub trait Middleware: Send + Sync {
+    fn before(&self, _plugin: &Plugin, _message: &Message) -> Flow {
+        Flow::Continue
+    }
+    fn after(&self, _plugin: &Plugin, _message: &Message, _outcome: &Outcome,
+             _elapsed: Duration) {}
+}
+
+/// The middleware a `Bot` runs messages through.
+pub struct Pipeline {
+    middleware: RwLock<Vec<Box<Middleware>>>
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline {
+            middleware: RwLock::new(Vec::new())
+        }
+    }
+
+    pub fn push(&self, middleware: Box<Middleware>) {
+        self.middleware.write().unwrap().push(middleware);
+    }
+
+    /// Handles `msg` with `plugin` through the middleware.
+    pub fn run(&self, plugin: &Plugin, msg: &Message, conn: &Connection) {
+        let middleware = self.middleware.read().unwrap();
+        let start = Instant::now();
+        let mut entered = 0;
+        let mut flow = Flow::Continue;
+        for m in middleware.iter() {
+            entered += 1;
+            flow = m.before(plugin, msg);
+            if flow != Flow::Continue { break }
+        }
+
+        // Catch a panic here so that `after` still hears of it.
+        let result = match flow {
+            Flow::Continue => panic::catch_unwind(AssertUnwindSafe(|| {
+                plugin.handle(msg, conn)
+            })).map(Some),
+            Flow::Reply(ref text) => {
+                if let Err(err) = conn.reply(msg, text) {
+                    println!("[Error] Failed to reply for middleware: {}", err);
+                }
+                Ok(None)
+            }
+            Flow::Stop => Ok(None)
+        };
+        let outcome = match result {
+            Ok(Some(Ok(()))) => Outcome::Handled,
+            Ok(Some(Err(ref err))) => Outcome::Failed(err),
+            Ok(None) => Outcome::Stopped,
+            Err(_) => Outcome::Panicked
+        };
+
+        let elapsed = start.elapsed();
+        for m in middleware[..entered].iter().rev() {
+            m.after(plugin, msg, &outcome, elapsed);
+        }
+    }
+}
+
+/// Logs handlers that fail or panic. Slow ones are reported by the `Bot`'s
+/// watchdog while they run instead.
+pub struct Log;
+
+impl Middleware for Log {
+    fn after(&self, plugin: &Plugin, _message: &Message, outcome: &Outcome,
+             _elapsed: Duration) {
+        match *outcome {
+            Outcome::Failed(err) => {
+                println!("[Error] {} failed to handle a message: {}",
+                         plugin.name(), err);
+            }
+            Outcome::Panicked => {
+                println!("[Error] {} panicked while handling a message",
+                         plugin.name());
+            }
+            Outcome::Handled | Outcome::Stopped => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use discord::model::ChannelId;
+    use bot::{Connection, Message};
+    use error::{Error, Result};
+    use plugin::Plugin;
+    use transport::{test_connection, test_message};
+    use super::{Flow, Middleware, Outcome, Pipeline};
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// Records its calls, and answers `before` with `flow`.
+    struct Step {
+        name: &'static str,
+        flow: Flow,
+        log: Calls
+    }
+
+    impl Middleware for Step {
+        fn before(&self, _plugin: &Plugin, _message: &Message) -> Flow {
+            self.log.lock().unwrap().push(format!("{} before", self.name));
+            self.flow.clone()
+        }
+
+        fn after(&self, _plugin: &Plugin, _message: &Message,
+                 outcome: &Outcome, _elapsed: Duration) {
+            let outcome = match *outcome {
+                Outcome::Handled => "handled",
+                Outcome::Failed(_) => "failed",
+                Outcome::Panicked => "panicked",
+                Outcome::Stopped => "stopped"
+            };
+            self.log.lock().unwrap()
+                .push(format!("{} after {}", self.name, outcome));
+        }
+    }
+
+    /// Records what it handles, and fails on "fail" and panics on "panic".
+    struct Handler {
+        log: Calls
+    }
+
+    impl Plugin for Handler {
+        fn new() -> Box<Plugin> {
+            Box::new(Handler { log: Arc::new(Mutex::new(Vec::new())) })
+        }
+
+        fn name(&self) -> &'static str {
+            "handler"
+        }
+
+        fn is_match(&self, _msg: &Message) -> bool {
+            true
+        }
+
+        fn handle(&self, msg: &Message, _conn: &Connection) -> Result<()> {
+            self.log.lock().unwrap().push("handle".to_string());
+            match &*msg.content() {
+                "fail" => Err(Error::Plugin("asked to".to_string())),
+                "panic" => panic!("asked to"),
+                _ => Ok(())
+            }
+        }
+    }
+
+    fn run(flows: &[(&'static str, Flow)], content: &str)
+        -> (Vec<String>, Vec<String>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let pipeline = Pipeline::new();
+        for &(name, ref flow) in flows {
+            pipeline.push(Box::new(Step {
+                name: name,
+                flow: flow.clone(),
+                log: log.clone()
+            }));
+        }
+        let plugin = Handler { log: log.clone() };
+        let (fake, conn) = test_connection();
+        pipeline.run(&plugin, &test_message(content), &conn);
+
+        let sent = fake.sent().into_iter().map(|(channel, text)| {
+            assert_eq!(channel, ChannelId(1));
+            text
+        }).collect();
+        let log = log.lock().unwrap().clone();
+        (log, sent)
+    }
+
+    #[test]
+    fn middleware_wraps_the_handler_in_order() {
+        let (log, sent) = run(&[("a", Flow::Continue), ("b", Flow::Continue)],
+                              "hi");
+        assert_eq!(log, vec!["a before", "b before", "handle",
+                             "b after handled", "a after handled"]);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn a_middleware_can_reply_instead_of_the_handler() {
+        let (log, sent) = run(&[("a", Flow::Continue),
+                                ("b", Flow::Reply("busy".to_string())),
+                                ("c", Flow::Continue)], "hi");
+        assert_eq!(log, vec!["a before", "b before", "b after stopped",
+                             "a after stopped"]);
+        assert_eq!(sent, vec!["<@1> busy"]);
+    }
+
+    #[test]
+    fn a_middleware_can_drop_the_message() {
+        let (log, sent) = run(&[("a", Flow::Stop), ("b", Flow::Continue)],
+                              "hi");
+        assert_eq!(log, vec!["a before", "a after stopped"]);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn failures_and_panics_reach_every_after() {
+        let (log, _) = run(&[("a", Flow::Continue)], "fail");
+        assert_eq!(log, vec!["a before", "handle", "a after failed"]);
+        let (log, _) = run(&[("a", Flow::Continue)], "panic");
+        assert_eq!(log, vec!["a before", "handle", "a after panicked"]);
+    }
+}
@@ -0,0 +1,385 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+use discord::Error as DiscordError;
+use discord::model::ChannelId;
+use transport::Transport;
+
+/// Messages Discord allows per channel in each `CHANNEL_PERIOD_MS`.
+const CHANNEL_LIMIT: u32 = 5;
+const CHANNEL_PERIOD_MS: u64 = 5000;
+/// Requests Discord allows in total in each `GLOBAL_PERIOD_MS`.
+const GLOBAL_LIMIT: u32 = 50;
+const GLOBAL_PERIOD_MS: u64 = 1000;
+/// How many times a rate limited message is retried before it is dropped.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// A fixed window rate limit bucket.
+struct Bucket {
+    remaining: u32,
+    reset: Instant
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Bucket {
+        Bucket {
+            remaining: 0,
+            reset: now
+        }
+    }
+
+    /// Returns when the next request may be made, or `None` if it may be
+    /// made now.
+    fn ready_at(&self, now: Instant) -> Option<Instant> {
+        if now >= self.reset || self.remaining > 0 {
+            None
+        } else {
+            Some(self.reset)
+        }
+    }
+
+    fn take(&mut self, now: Instant, limit: u32, period: Duration) {
+        if now >= self.reset {
+            self.remaining = limit;
+            self.reset = now + period;
+        }
+        self.remaining = self.remaining.saturating_sub(1);
+    }
+
+    fn retry_after(&mut self, now: Instant, ms: u64) {
+        self.remaining = 0;
+        self.reset = cmp::max(self.reset, now + Duration::from_millis(ms));
+    }
+}
+
+struct Outgoing {
+    channel: ChannelId,
+    text: String,
+    attempts: u32
+}
+
+struct State {
+    queue: VecDeque<Outgoing>,
+    channels: HashMap<ChannelId, Bucket>,
+    global: Bucket,
+    // Whether a message has been taken off the queue but not yet sent.
+    sending: bool,
+    closed: bool
+}
+
+impl State {
+    /// Returns when a message may next be sent to `channel`, or `None` if it
+    /// may be sent now.
+    fn ready_at(&self, channel: ChannelId, now: Instant) -> Option<Instant> {
+        let channel = self.channels.get(&channel)
+            .and_then(|b| b.ready_at(now));
+        match (channel, self.global.ready_at(now)) {
+            (Some(a), Some(b)) => Some(cmp::max(a, b)),
+            (a, b) => a.or(b)
+        }
+    }
+
+    fn take(&mut self, channel: ChannelId, now: Instant) {
+        // Forget channels whose window has passed so the map does not grow
+        // with every channel the bot has ever talked in.
+        self.channels.retain(|_, b| b.reset > now);
+        self.channels.entry(channel).or_insert_with(|| Bucket::new(now))
+            .take(now, CHANNEL_LIMIT, Duration::from_millis(CHANNEL_PERIOD_MS));
+        self.global.take(now, GLOBAL_LIMIT,
+                         Duration::from_millis(GLOBAL_PERIOD_MS));
+    }
+
+    fn retry_after(&mut self, channel: ChannelId, ms: u64) {
+        let now = Instant::now();
+        self.channels.entry(channel).or_insert_with(|| Bucket::new(now))
+            .retry_after(now, ms);
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Signalled when a message is queued, a bucket changes or the outbox
+    // is closed.
+    changed: Condvar
+}
+
+/// An `Outbox` sends queued messages on its own thread, keeping within
+/// Discord's per-channel and global rate limits and retrying messages that
+/// were rate limited anyway. Messages queued for the same channel while it
+/// waits are sent together where they fit in one message.
+pub struct Outbox {
+    shared: Arc<Shared>
+}
+
+impl Outbox {
+    pub fn new(transport: Arc<Transport>, max_len: usize) -> Outbox {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                channels: HashMap::new(),
+                global: Bucket::new(Instant::now()),
+                sending: false,
+                closed: false
+            }),
+            changed: Condvar::new()
+        });
+
+        let shared_1 = shared.clone();
+        thread::Builder::new()
+            .name("outbox".to_string())
+            .spawn(move || send_queued(shared_1, transport, max_len))
+            .expect("Failed to spawn outbox");
+
+        Outbox {
+            shared: shared
+        }
+    }
+
+    /// Queues a message to be sent as soon as the rate limits allow.
+    pub fn push(&self, channel: ChannelId, text: String) {
+        self.lock().queue.push_back(Outgoing {
+            channel: channel,
+            text: text,
+            attempts: 0
+        });
+        self.shared.changed.notify_all();
+    }
+
+    /// The number of messages waiting to be sent.
+    pub fn depth(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    /// Blocks until a message may be sent to `channel` and counts it against
+    /// the rate limits. Used for messages sent directly.
+    pub fn acquire(&self, channel: ChannelId) {
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            match state.ready_at(channel, now) {
+                None => {
+                    state.take(channel, now);
+                    return
+                }
+                Some(at) => {
+                    state = self.shared.changed.wait_timeout(state, at - now)
+                        .unwrap().0;
+                }
+            }
+        }
+    }
+
+    /// Records that Discord asked to wait `ms` before sending to `channel`.
+    pub fn rate_limited(&self, channel: ChannelId, ms: u64) {
+        self.lock().retry_after(channel, ms);
+        self.shared.changed.notify_all();
+    }
+
+    /// Blocks until every queued message has been sent or `timeout` has
+    /// passed. Returns `false` on timeout.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        while state.sending || !state.queue.is_empty() {
+            let now = Instant::now();
+            if now >= deadline { return false }
+            state = self.shared.changed.wait_timeout(state, deadline - now)
+                .unwrap().0;
+        }
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.shared.state.lock().unwrap()
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.lock().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+fn send_queued(shared: Arc<Shared>, transport: Arc<Transport>, max_len: usize) {
+    loop {
+        let msg = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.closed { return }
+                let now = Instant::now();
+                match next_ready(&mut state, now, max_len) {
+                    Ok(msg) => {
+                        state.sending = true;
+                        break msg
+                    }
+                    Err(Some(at)) => {
+                        state = shared.changed.wait_timeout(state, at - now)
+                            .unwrap().0;
+                    }
+                    Err(None) => state = shared.changed.wait(state).unwrap()
+                }
+            }
+        };
+
+        let result = transport.send_message(msg.channel, &msg.text);
+        let mut state = shared.state.lock().unwrap();
+        state.sending = false;
+        match result {
+            Ok(_) => {}
+            Err(DiscordError::RateLimited(ms)) => {
+                state.retry_after(msg.channel, ms);
+                if msg.attempts < MAX_ATTEMPTS {
+                    println!("[Warning] Rate limited in {}, retrying in {}ms",
+                             msg.channel.0, ms);
+                    state.queue.push_front(msg);
+                } else {
+                    println!("[Error] Dropping message to {} after {} attempts",
+                             msg.channel.0, msg.attempts);
+                }
+            }
+            Err(err) => {
+                println!("[Error] Failed to send queued message: {:?}", err);
+            }
+        }
+        shared.changed.notify_all();
+    }
+}
+
+/// Takes the first queued message whose channel is not rate limited,
+/// together with any later messages to the same channel that fit alongside
+/// it. Otherwise returns when the earliest one may be sent, or `None` if the
+/// queue is empty.
+fn next_ready(state: &mut State, now: Instant, max_len: usize)
+    -> Result<Outgoing, Option<Instant>> {
+    let mut earliest = None;
+    let mut index = None;
+    for (i, msg) in state.queue.iter().enumerate() {
+        match state.ready_at(msg.channel, now) {
+            None => {
+                index = Some(i);
+                break
+            }
+            Some(at) => {
+                earliest = Some(earliest.map_or(at, |e| cmp::min(e, at)));
+            }
+        }
+    }
+    let index = match index {
+        Some(i) => i,
+        None => return Err(earliest)
+    };
+
+    let mut msg = state.queue.remove(index).unwrap();
+    msg.attempts += 1;
+    let mut len = msg.text.chars().count();
+    let mut i = index;
+    while i < state.queue.len() {
+        if state.queue[i].channel == msg.channel {
+            let next_len = state.queue[i].text.chars().count();
+            if len + 1 + next_len > max_len { break }
+            let next = state.queue.remove(i).unwrap();
+            msg.text.push('\n');
+            msg.text.push_str(&next.text);
+            len += 1 + next_len;
+        } else {
+            i += 1;
+        }
+    }
+
+    state.take(msg.channel, now);
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use transport::FakeTransport;
+    use super::*;
+
+    fn state(now: Instant, queued: Vec<(u64, &str)>) -> State {
+        State {
+            queue: queued.into_iter()
+                .map(|(channel, text)| Outgoing {
+                    channel: ChannelId(channel),
+                    text: text.to_string(),
+                    attempts: 0
+                })
+                .collect::<VecDeque<Outgoing>>(),
+            channels: HashMap::new(),
+            global: Bucket::new(now),
+            sending: false,
+            closed: false
+        }
+    }
+
+    #[test]
+    fn each_channel_waits_for_its_own_window() {
+        let now = Instant::now();
+        let mut state = state(now, vec![]);
+        for _ in 0..CHANNEL_LIMIT {
+            assert_eq!(state.ready_at(ChannelId(1), now), None);
+            state.take(ChannelId(1), now);
+        }
+        let reset = now + Duration::from_millis(CHANNEL_PERIOD_MS);
+        assert_eq!(state.ready_at(ChannelId(1), now), Some(reset));
+        assert_eq!(state.ready_at(ChannelId(2), now), None);
+        assert_eq!(state.ready_at(ChannelId(1), reset), None);
+    }
+
+    #[test]
+    fn retry_after_holds_the_bucket_back() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now);
+        bucket.take(now, CHANNEL_LIMIT, Duration::from_millis(CHANNEL_PERIOD_MS));
+        bucket.retry_after(now, 200);
+        assert_eq!(bucket.ready_at(now), Some(now + Duration::from_millis(200)));
+
+        // A shorter wait does not cut a longer one short.
+        bucket.retry_after(now, 100);
+        assert_eq!(bucket.ready_at(now), Some(now + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn merges_a_burst_to_the_same_channel() {
+        let now = Instant::now();
+        let mut state = state(now, vec![(1, "a"), (2, "b"), (1, "c"),
+                                        (1, "dddd"), (1, "e")]);
+        let msg = next_ready(&mut state, now, 4).ok().unwrap();
+        assert_eq!((msg.channel, msg.text.as_str()), (ChannelId(1), "a\nc"));
+        assert_eq!(msg.attempts, 1);
+        let left = state.queue.iter().map(|m| m.text.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(left, vec!["b", "dddd", "e"]);
+    }
+
+    #[test]
+    fn says_when_the_next_message_may_go() {
+        let now = Instant::now();
+        let mut state = state(now, vec![]);
+        assert_eq!(next_ready(&mut state, now, 10).err(), Some(None));
+
+        for _ in 0..CHANNEL_LIMIT {
+            state.take(ChannelId(1), now);
+        }
+        state.queue.push_back(Outgoing {
+            channel: ChannelId(1),
+            text: "late".to_string(),
+            attempts: 0
+        });
+        let reset = now + Duration::from_millis(CHANNEL_PERIOD_MS);
+        assert_eq!(next_ready(&mut state, now, 10).err(), Some(Some(reset)));
+    }
+
+    #[test]
+    fn retries_a_rate_limited_message() {
+        let fake = Arc::new(FakeTransport::new());
+        let outbox = Outbox::new(fake.clone(), 2000);
+        fake.rate_limit_next(100);
+        outbox.push(ChannelId(1), "a".to_string());
+        outbox.push(ChannelId(1), "b".to_string());
+        assert!(outbox.flush(Duration::from_secs(5)));
+        assert_eq!(fake.sent(), vec![(ChannelId(1), "a\nb".to_string())]);
+    }
+}
@@ -1,15 +1,152 @@
+use std::ops::BitOr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use discord::State;
+use discord::model::{ChannelId, MessageId, ServerId, Reaction, Member, User,
+                     Presence, LiveServer};
+use access::Access;
 use bot::{Connection, Message};
+use command::Command;
+use config::{self, Settings};
+use limit::RateLimit;
+use error::Result;
 
 /// A `Plugin` is a user implemented handler for specific messages. A `Plugin`
-/// must implement `Send` as it is shared between threads. It must also return
-/// a `Box<Plugin>` to guarantee the `Plugin` owns its own data.
-pub trait Plugin: Send {
+/// must implement `Send` and `Sync` as it is shared between threads: `is_match`
+/// is called from the event loop while `handle` runs on a worker. It must also
+/// return a `Box<Plugin>` to guarantee the `Plugin` owns its own data.
+///
+/// `handle` takes `&self` so that matching never waits on a running handler.
+/// Messages for one plugin are still handled one at a time and in order, so
+/// any state can sit behind a `Mutex` without contention.
+///
+/// The `on_*` methods are called for lifecycle and other gateway events. All
+/// but `on_ready` and `on_shutdown` go through the same queue as `handle`.
+/// They do nothing unless overridden, and a gateway event is only queued
+/// for the plugins whose `events` ask for it.
+pub trait Plugin: Send + Sync {
     fn new() -> Box<Plugin> where Self: Sized;
+    /// A short name used when reporting on the plugin.
+    fn name(&self) -> &'static str;
+    /// What the plugin does, shown by `!help`.
+    fn description(&self) -> &'static str { "" }
+    /// The commands the plugin handles, shown by `!help`. A plugin built on
+    /// a `Router` returns its `commands()`.
+    fn commands(&self) -> Vec<Command> { Vec::new() }
+    /// Who may use the plugin, unless a command says otherwise. Messages
+    /// from anyone else are answered with a refusal instead of handled.
+    fn access(&self) -> Access { Access::Everyone }
+    /// How often `command` may be used, checked before a matched message
+    /// is queued. `command` is the one of `commands` the message calls, or
+    /// `None` if it calls none of them. Each command is counted on its own.
+    /// Returning `None` means as often as anyone likes.
+    fn rate_limit(&self, _command: Option<&Command>) -> Option<RateLimit> {
+        None
+    }
+    /// Whether the author of `message` may use `command`. Messages calling
+    /// it are refused otherwise, and `!help` leaves it out.
+    fn can_use(&self, _command: &Command, _message: &Message) -> bool { true }
     fn is_match(&self, message: &Message) -> bool;
-    fn handle(&mut self, message: &Message, conn: &Connection);
+    /// Whether a matched message stops here, so that the plugins after this
+    /// one never see it. Only asked once the message has been queued: one
+    /// that was refused, rate limited or dropped goes on to the next plugin.
+    fn consumes(&self, _message: &Message) -> bool { false }
+    /// Where the plugin goes in the order messages are matched in: higher
+    /// goes first, and plugins of the same priority go in the order they
+    /// were registered. Read once when the plugin is registered.
+    fn priority(&self) -> i32 { 0 }
+    /// Handles a matched message. An error is passed on to the middleware,
+    /// where `middleware::Log` logs it.
+    fn handle(&self, message: &Message, conn: &Connection) -> Result<()>;
+
+    /// How often `on_tick` is called, read once when the plugin is
+    /// registered. `None` means never.
+    fn tick_interval(&self) -> Option<Duration> { None }
+    /// The gateway events the plugin's `on_*` hooks are called for, read
+    /// once when the plugin is registered. None by default.
+    fn events(&self) -> Events { Events::none() }
+    /// Whether the bot shows as typing in the channel while `handle` runs.
+    /// Worth turning on for handlers that take more than a moment.
+    fn typing(&self) -> bool { false }
+    /// Applies new `[plugins.<name>]` settings when the config is reloaded.
+    /// Settings that are wrong must be rejected with nothing changed. The
+    /// default accepts only empty settings.
+    fn reconfigure(&self, settings: &Settings) -> config::Result<()> {
+        settings.check_keys(&[])
+    }
+
+    /// Called from the event loop once each gateway session is ready.
+    fn on_ready(&self, _state: &State, _conn: &Connection) {}
+    /// Called once all queued handlers have finished and the bot is stopping.
+    fn on_shutdown(&self, _conn: &Connection) {}
+    fn on_tick(&self, _conn: &Connection) {}
+
+    /// Called when a message is edited. `content` is `None` if the text did
+    /// not change, e.g. when only an embed was added.
+    fn on_message_edit(&self, _channel: ChannelId, _message: MessageId,
+                       _content: Option<&str>, _conn: &Connection) {}
+    fn on_message_delete(&self, _channel: ChannelId, _message: MessageId,
+                         _conn: &Connection) {}
+    fn on_reaction_add(&self, _reaction: &Reaction, _conn: &Connection) {}
+    fn on_reaction_remove(&self, _reaction: &Reaction, _conn: &Connection) {}
+    fn on_member_join(&self, _server: ServerId, _member: &Member,
+                      _conn: &Connection) {}
+    fn on_member_leave(&self, _server: ServerId, _user: &User,
+                       _conn: &Connection) {}
+    /// Called when a user's status or game changes. `server` is `None` for
+    /// users seen through a private channel or group.
+    fn on_presence_update(&self, _server: Option<ServerId>, _presence: &Presence,
+                          _conn: &Connection) {}
+    /// Called when a server becomes available, both at startup and when the
+    /// bot joins a new one.
+    fn on_server_create(&self, _server: &LiveServer, _conn: &Connection) {}
+}
+
+/// A set of gateway events, as returned by `Plugin::events`. Combine them
+/// with `|`, e.g. `REACTIONS | MEMBERS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Events(u32);
+
+/// `on_message_edit`.
+pub const MESSAGE_EDITS: Events = Events(1);
+/// `on_message_delete`.
+pub const MESSAGE_DELETES: Events = Events(1 << 1);
+/// `on_reaction_add` and `on_reaction_remove`.
+pub const REACTIONS: Events = Events(1 << 2);
+/// `on_member_join` and `on_member_leave`.
+pub const MEMBERS: Events = Events(1 << 3);
+/// `on_presence_update`.
+pub const PRESENCES: Events = Events(1 << 4);
+/// `on_server_create`.
+pub const SERVERS: Events = Events(1 << 5);
+
+impl Events {
+    pub fn none() -> Events {
+        Events(0)
+    }
+
+    /// Whether every event in `other` is in the set.
+    pub fn contains(&self, other: Events) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Events {
+    type Output = Events;
+
+    fn bitor(self, other: Events) -> Events {
+        Events(self.0 | other.0)
+    }
 }
 
 /// A `DefaultPlugin` is an empty struct used for the default implementation of
 /// `Plugin::new`. Use the default implementation if you do not need to store
 /// any data within a `Plugin`.
 pub struct DefaultPlugin;
+
+/// Locks a `Mutex` holding plugin state. If a handler panicked while holding
+/// the lock, the state is used as it was left rather than failing every
+/// later message too.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
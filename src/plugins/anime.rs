@@ -1,89 +1,268 @@
-extern crate regex;
 extern crate nineanime;
 
-use ::plugin::Plugin;
+use std::sync::Mutex;
+
+use ::plugin::{self, Plugin};
 use ::bot::{Connection, Message};
+use ::command::{Args, ArgKind, Command, Router};
+use ::config::{self, Settings};
+use ::embed::Embed;
+use ::error::{Error, Result};
+
+const NO_LAST_EPISODE: &'static str = "No last episode found";
+const NO_MATCHES: &'static str = "Could not find any matches";
+const SEARCH_FAILED: &'static str = "Could not search 9anime right now";
+const DEFAULT_QUALITY: &'static str = "720p";
+/// The keys read from `[plugins.anime]`.
+pub const SETTINGS: &'static [&'static str] = &["quality"];
+
+/// A file of an episode, such as its 720p version.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub label: String,
+    pub file: String
+}
+
+/// Where episodes are looked up: `NineAnime`, or something canned in tests.
+pub trait Episodes: Send + Sync {
+    /// The files of episode `ep` of the best match for `title`, or `None` if
+    /// there is no such anime or episode. `Err` if the search itself failed.
+    fn find(&self, title: &str, ep: usize)
+        -> ::std::result::Result<Option<Vec<Source>>, String>;
+}
+
+/// Looks episodes up on 9anime.
+pub struct NineAnime;
+
+impl Episodes for NineAnime {
+    fn find(&self, title: &str, ep: usize)
+        -> ::std::result::Result<Option<Vec<Source>>, String> {
+        let matches = nineanime::search(title)
+            .map_err(|e| format!("{:?}", e))?;
+        let anime = match matches.first() {
+            Some(a) => a,
+            None => return Ok(None)
+        };
+        let files = match anime.files(ep) {
+            Ok(f) => f,
+            Err(_) => return Ok(None)
+        };
+        Ok(Some(files.data.iter()
+                .map(|d| Source {
+                    label: d.label.to_string(),
+                    file: d.file.to_string()
+                })
+                .collect()))
+    }
+}
 
 pub struct AnimePlugin {
-    regex: regex::Regex,
-    last_search: Option<String>,
-    last_ep: Option<usize>
+    episodes: Box<Episodes>,
+    quality: Mutex<String>,
+    last_search: Mutex<Option<String>>,
+    last_ep: Mutex<Option<usize>>,
+    commands: Router<AnimePlugin>
 }
 
 impl Plugin for AnimePlugin {
     fn new() -> Box<Plugin> {
-        Box::new(AnimePlugin{
-            regex: regex::Regex::new(r"^!9a\s(\d+),?\s?(.+)").unwrap(),
-            last_search: None,
-            last_ep: None
-        })
+        Box::new(AnimePlugin::with_quality(DEFAULT_QUALITY))
+    }
+
+    fn name(&self) -> &'static str {
+        "anime"
+    }
+
+    fn description(&self) -> &'static str {
+        "Finds episodes on 9anime"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    fn typing(&self) -> bool {
+        true
+    }
+
+    fn reconfigure(&self, settings: &Settings) -> config::Result<()> {
+        *plugin::lock(&self.quality) = read_quality(settings)?;
+        Ok(())
     }
 
     fn is_match(&self, msg: &Message) -> bool {
-        self.regex.is_match(&msg.content())
-            || msg.content() == "!9a next"
+        self.commands.is_match(msg)
     }
 
-    fn handle(&mut self, msg: &Message, conn: &Connection) {
-        let content = msg.content();
-        let ep: usize;
-        let title: String;
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+}
 
-        if content == "!9a next" {
-            ep = match self.last_ep {
-                Some(ep) => ep + 1,
-                None => {
-                    conn.reply(msg, "No last episode found");
-                    return
-                }
-            };
-            title = match self.last_search {
-                Some(ref title) => title.to_string(),
-                None => {
-                    conn.reply(msg, "No last episode found");
-                    return
-                }
-            }
-        } else {
-            let caps = match self.regex.captures(&content) {
-                Some(c) => c,
-                None => {
-                    conn.reply(msg, "Could not find any matches");
-                    return
-                }
-            };
+impl AnimePlugin {
+    /// Creates an `AnimePlugin` from its `[plugins.anime]` settings:
+    /// `quality`, the label of the files to link, such as "720p".
+    pub fn with_settings(settings: &Settings) -> config::Result<Box<Plugin>> {
+        let quality = read_quality(settings)?;
+        Ok(Box::new(AnimePlugin::with_quality(&quality)))
+    }
+
+    fn with_quality(quality: &str) -> AnimePlugin {
+        AnimePlugin::with_episodes(Box::new(NineAnime), quality)
+    }
 
-            ep = caps.get(1).unwrap().as_str().parse::<usize>().unwrap();
-            title = caps.get(2).unwrap().as_str().to_string();
+    /// Creates an `AnimePlugin` that looks episodes up in `episodes`.
+    pub fn with_episodes(episodes: Box<Episodes>, quality: &str)
+        -> AnimePlugin {
+        AnimePlugin {
+            episodes: episodes,
+            quality: Mutex::new(quality.to_string()),
+            last_search: Mutex::new(None),
+            last_ep: Mutex::new(None),
+            commands: Router::new()
+                .add(Command::new("9a")
+                     .arg("next", ArgKind::Literal("next"))
+                     .description("Finds the episode after the last one found"),
+                     AnimePlugin::next)
+                .add(Command::new("9a")
+                     .arg("episode", ArgKind::Int)
+                     .arg("title", ArgKind::Rest)
+                     .description("Finds links to an episode of an anime"),
+                     AnimePlugin::search)
         }
+    }
 
-        let matches = nineanime::search(&title).unwrap();
-        let anime = match matches.first() {
-            Some(a) => a,
-            None => {
-                conn.reply(msg, "Could not find any matches");
-                return
-            }
+    fn next(&self, _args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let ep = match *plugin::lock(&self.last_ep) {
+            Some(ep) => ep + 1,
+            None => return conn.reply(msg, NO_LAST_EPISODE).map(|_| ())
         };
-        let files = match anime.files(ep) {
-            Ok(f) => f,
-            Err(_) => {
-                conn.reply(msg, "Could not find any matches");
-                return
+        let title = match *plugin::lock(&self.last_search) {
+            Some(ref title) => title.to_string(),
+            None => return conn.reply(msg, NO_LAST_EPISODE).map(|_| ())
+        };
+        self.find(ep, title, msg, conn)
+    }
+
+    fn search(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let ep = args.int("episode").unwrap();
+        if ep < 0 {
+            return conn.reply(msg, NO_MATCHES).map(|_| ())
+        }
+        self.find(ep as usize, args.str("title").unwrap().to_string(), msg,
+                  conn)
+    }
+
+    fn find(&self, ep: usize, title: String, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let files = match self.episodes.find(&title, ep) {
+            Ok(Some(files)) => files,
+            Ok(None) => return conn.reply(msg, NO_MATCHES).map(|_| ()),
+            Err(err) => {
+                conn.reply(msg, SEARCH_FAILED)?;
+                return Err(Error::Plugin(format!("Failed to search 9anime \
+                                                  for {}: {}", title, err)))
             }
         };
-        let direct_links = files.data.iter()
-            .cloned()
-            .filter(|d| &*d.label == "720p")
-            .map(|d| format!("{} ({})", d.file, d.label))
+        let quality = plugin::lock(&self.quality).clone();
+        let direct_links = files.iter()
+            .filter(|d| d.label == quality)
+            .map(|d| format!("[{}]({})", d.label, d.file))
             .collect::<Vec<String>>();
+        if direct_links.is_empty() {
+            return conn.reply(msg, NO_MATCHES).map(|_| ())
+        }
+
+        let embed = Embed::new()
+            .title(&format!("{} episode {}", title, ep))
+            .description(&direct_links.join("\n"));
+        conn.reply_embed(msg, &embed)?;
+
+        *plugin::lock(&self.last_ep) = Some(ep);
+        *plugin::lock(&self.last_search) = Some(title);
+        Ok(())
+    }
+}
 
-        conn.reply(msg, "Found links:");
-        for link in direct_links {
-            conn.send(msg, &link);
+fn read_quality(settings: &Settings) -> config::Result<String> {
+    settings.check_keys(SETTINGS)?;
+    Ok(settings.str("quality")?.unwrap_or_else(|| DEFAULT_QUALITY.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use ::bot::Connection;
+    use ::plugin::Plugin;
+    use ::transport::{FakeTransport, test_connection, test_message as message};
+    use super::*;
+
+    /// Has episodes 1 and 2 of "k-on", and fails every search for "down".
+    struct Canned;
+
+    impl Episodes for Canned {
+        fn find(&self, title: &str, ep: usize)
+            -> ::std::result::Result<Option<Vec<Source>>, String> {
+            if title == "down" { return Err("timed out".to_string()) }
+            if title != "k-on" || ep == 0 || ep > 2 { return Ok(None) }
+            Ok(Some(vec![
+                Source {
+                    label: "480p".to_string(),
+                    file: format!("http://a/{}-480.mp4", ep)
+                },
+                Source {
+                    label: "720p".to_string(),
+                    file: format!("http://a/{}-720.mp4", ep)
+                }
+            ]))
         }
+    }
+
+    fn setup() -> (AnimePlugin, Arc<FakeTransport>, Connection) {
+        let (fake, conn) = test_connection();
+        (AnimePlugin::with_episodes(Box::new(Canned), "720p"), fake, conn)
+    }
+
+    #[test]
+    fn links_the_episode_in_the_chosen_quality() {
+        let (plugin, fake, conn) = setup();
+        plugin.handle(&message("!9a 2 k-on"), &conn).unwrap();
+
+        let embeds = fake.embeds();
+        assert_eq!(embeds.len(), 1);
+        assert_eq!(embeds[0].1.title, Some("k-on episode 2".to_string()));
+        assert_eq!(embeds[0].1.description,
+                   Some("[720p](http://a/2-720.mp4)".to_string()));
+    }
+
+    #[test]
+    fn next_finds_the_following_episode() {
+        let (plugin, fake, conn) = setup();
+        plugin.handle(&message("!9a next"), &conn).unwrap();
+        assert_eq!(fake.sent()[0].1, format!("<@1> {}", NO_LAST_EPISODE));
+
+        plugin.handle(&message("!9a 1 k-on"), &conn).unwrap();
+        plugin.handle(&message("!9a next"), &conn).unwrap();
+        let titles = fake.embeds().into_iter()
+            .map(|(_, embed)| embed.title.unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(titles, vec!["k-on episode 1", "k-on episode 2"]);
+    }
+
+    #[test]
+    fn says_when_nothing_matches() {
+        let (plugin, fake, conn) = setup();
+        plugin.handle(&message("!9a 3 k-on"), &conn).unwrap();
+        assert_eq!(fake.sent()[0].1, format!("<@1> {}", NO_MATCHES));
+        assert!(fake.embeds().is_empty());
+    }
 
-        self.last_ep = Some(ep);
-        self.last_search = Some(title.clone().to_string());
+    #[test]
+    fn a_failed_search_is_an_error() {
+        let (plugin, fake, conn) = setup();
+        assert!(plugin.handle(&message("!9a 1 down"), &conn).is_err());
+        assert_eq!(fake.sent()[0].1, format!("<@1> {}", SEARCH_FAILED));
     }
 }
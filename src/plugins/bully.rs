@@ -1,5 +1,6 @@
 use ::plugin::Plugin;
 use ::bot::{Connection, Message};
+use ::error::Result;
 
 pub struct BullyPlugin;
 
@@ -8,6 +9,14 @@ impl Plugin for BullyPlugin {
         Box::new(BullyPlugin{})
     }
 
+    fn name(&self) -> &'static str {
+        "bully"
+    }
+
+    fn description(&self) -> &'static str {
+        "Answers _bully shy imouto_"
+    }
+
     fn is_match(&self, msg: &Message) -> bool {
         // content() clones, so prefer to do it once.
         let content = msg.content();
@@ -15,8 +24,8 @@ impl Plugin for BullyPlugin {
             content.ends_with("_")
     }
 
-    fn handle(&mut self, msg: &Message, conn: &Connection) {
-        conn.send(msg, "pls no bully >.<;;;");
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        conn.send(msg, "pls no bully >.<;;;").map(|_| ())
     }
 }
 
@@ -27,13 +36,21 @@ impl Plugin for HugPlugin {
         Box::new(HugPlugin{})
     }
 
+    fn name(&self) -> &'static str {
+        "hug"
+    }
+
+    fn description(&self) -> &'static str {
+        "Answers _hug shy imouto_ with a hug"
+    }
+
     fn is_match(&self, msg: &Message) -> bool {
         let content = msg.content();
         content.starts_with("_hug shy imouto") &&
             content.ends_with("_")
     }
 
-    fn handle(&mut self, msg: &Message, conn: &Connection) {
-        conn.send(msg, &format!("_hug {}_", msg.author().name));
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        conn.send(msg, &format!("_hug {}_", msg.author().name)).map(|_| ())
     }
 }
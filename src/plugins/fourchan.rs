@@ -3,25 +3,128 @@ extern crate regex;
 
 use std::error::Error;
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 
 use ::bot::{Connection, Message};
-use ::plugin::Plugin;
+use ::command::{Args, ArgKind, Command, Router};
+use ::config::{self, Settings};
+use ::embed::Embed;
+use ::error::{self, Result};
+use ::plugin::{self, Plugin};
+
+/// The most threads shown for a single search.
+const MAX_RESULT_EMBEDS: usize = 5;
+/// The keys read from `[plugins.4chan]`.
+pub const SETTINGS: &'static [&'static str] = &["boards"];
+
+/// A thread found on a board.
+#[derive(Clone, Debug)]
+pub struct Found {
+    pub title: String,
+    pub url: String,
+    pub image: Option<String>
+}
+
+/// Why a board could not be read.
+#[derive(Clone, Debug)]
+pub enum LookupError {
+    NoSuchBoard,
+    Failed(String)
+}
+
+/// Where boards are read from: `Clover`, or something canned in tests.
+pub trait Chan: Send + Sync {
+    /// The threads on `board` that match `query`.
+    fn search(&self, board: &str, query: &str)
+        -> ::std::result::Result<Vec<Found>, LookupError>;
+
+    /// The URL of the thread on `board` that the image at `image` was posted
+    /// in, if it is still there.
+    fn thread_of(&self, board: &str, image: &str)
+        -> ::std::result::Result<Option<String>, LookupError>;
+}
+
+/// Reads boards from 4chan, keeping each board it has read for the next
+/// lookup.
+pub struct Clover {
+    boards: Mutex<HashMap<String, clover::Board>>,
+    client: Arc<Mutex<clover::Client>>
+}
+
+impl Clover {
+    pub fn new() -> Clover {
+        Clover {
+            boards: Mutex::new(HashMap::new()),
+            client: Arc::new(Mutex::new(clover::Client::new().unwrap()))
+        }
+    }
+
+    /// Calls `f` with the board called `name` once its catalog is fresh.
+    fn with_board<T, F>(&self, name: &str, f: F)
+        -> ::std::result::Result<T, LookupError>
+        where F: FnOnce(&mut clover::Board)
+                        -> ::std::result::Result<T, LookupError> {
+        let mut boards = plugin::lock(&self.boards);
+        if !boards.contains_key(name) {
+            let board = match clover::Board::new(self.client.to_owned(), name) {
+                Ok(b) => b,
+                Err(ref e) if e.description() == "Invalid board name" => {
+                    return Err(LookupError::NoSuchBoard)
+                }
+                Err(e) => return Err(LookupError::Failed(e.description()
+                                                         .to_string()))
+            };
+            boards.insert(name.to_string(), board);
+        }
+        let board = boards.get_mut(name).unwrap();
+        board.catalog().map_err(failed)?;
+        f(board)
+    }
+}
+
+impl Chan for Clover {
+    fn search(&self, board: &str, query: &str)
+        -> ::std::result::Result<Vec<Found>, LookupError> {
+        self.with_board(board, |board| {
+            let threads = board.find_cached(query).map_err(failed)?;
+            Ok(threads.iter()
+               .map(|thread| Found {
+                   title: sub_or_com(thread),
+                   url: thread.url(),
+                   image: thread.image_urls().first().cloned()
+               })
+               .collect())
+        })
+    }
+
+    fn thread_of(&self, board: &str, image: &str)
+        -> ::std::result::Result<Option<String>, LookupError> {
+        self.with_board(board, |board| {
+            let image = image.to_string();
+            Ok(plugin::lock(&board.thread_cache).threads.values()
+               .find(|thread| thread.image_urls().contains(&image))
+               .map(|thread| thread.url()))
+        })
+    }
+}
 
 pub struct FourchanImagePlugin {
-    boards: HashMap<String, clover::Board>,
-    client: Arc<Mutex<clover::Client>>,
+    chan: Box<Chan>,
     img_regex: regex::Regex,
 }
 
 impl Plugin for FourchanImagePlugin {
     fn new() -> Box<Plugin> {
-        Box::new(FourchanImagePlugin {
-            boards: HashMap::new(),
-            client: Arc::new(Mutex::new(clover::Client::new().unwrap())),
-            img_regex: regex::Regex::new(r"https?://i.4cdn.org/([^/]+)/\d+\.[^\s]+")
-                .unwrap()
-        })
+        Box::new(FourchanImagePlugin::with_chan(Box::new(Clover::new())))
+    }
+
+    fn name(&self) -> &'static str {
+        "4chan-image"
+    }
+
+    fn description(&self) -> &'static str {
+        "Says which thread a linked 4chan image is from"
     }
 
     fn is_match(&self, msg: &Message) -> bool {
@@ -29,99 +132,340 @@ impl Plugin for FourchanImagePlugin {
     }
 
     // TODO doesn't work. 
-    fn handle(&mut self, msg: &Message, conn: &Connection) {
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
         let content = msg.content();
-        let captures = self.img_regex.captures(&content);
-        if captures.is_some() {
-            let caps = captures.unwrap();
-            let url = caps.get(0).map_or("", |u| u.as_str());
-            let board_name = caps.get(1).map_or("", |b| b.as_str());
-
-            let empty_b = match clover::Board::new(self.client.to_owned(), board_name) {
-                Ok(b) => b,
-                Err(_) => return
-            };
+        let caps = match self.img_regex.captures(&content) {
+            Some(caps) => caps,
+            None => return Ok(())
+        };
+        let url = caps.get(0).map_or("", |u| u.as_str());
+        let board_name = caps.get(1).map_or("", |b| b.as_str());
 
-            let board = self.boards.entry(board_name.to_string()).or_insert(empty_b);
-            let _ = board.catalog().unwrap();
-            for thread in board.thread_cache.lock().unwrap().threads.values() {
-                if thread.image_urls().contains(&url.to_string()) {
-                    conn.reply(msg, &format!("posted an image from {}", thread.url()));
-                    return
-                }
+        match self.chan.thread_of(board_name, url) {
+            Ok(Some(thread)) => {
+                conn.reply(msg, &format!("posted an image from {}", thread))?;
             }
+            Ok(None) | Err(LookupError::NoSuchBoard) => {}
+            Err(LookupError::Failed(err)) => {
+                return Err(lookup_failed(board_name, &err))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FourchanImagePlugin {
+    /// Creates a `FourchanImagePlugin` that reads boards from `chan`.
+    pub fn with_chan(chan: Box<Chan>) -> FourchanImagePlugin {
+        FourchanImagePlugin {
+            chan: chan,
+            img_regex: regex::Regex::new(r"https?://i.4cdn.org/([^/]+)/\d+\.[^\s]+")
+                .unwrap()
         }
     }
 }
 
 pub struct FourchanPlugin {
-    boards: HashMap<String, clover::Board>,
-    client: Arc<Mutex<clover::Client>>,
-    regex: regex::Regex,
+    chan: Box<Chan>,
+    // The boards that may be searched, or `None` for any.
+    allowed: Mutex<Option<Vec<String>>>,
+    commands: Router<FourchanPlugin>,
 }
 
 impl Plugin for FourchanPlugin {
     fn new() -> Box<Plugin> {
-        Box::new(FourchanPlugin {
-            boards: HashMap::new(),
-            client: Arc::new(Mutex::new(clover::Client::new().unwrap())),
-            regex: regex::Regex::new(r"!4c\s([A-Za-z0-9]+),(.+)").unwrap()
-        })
+        Box::new(FourchanPlugin::with_boards(Box::new(Clover::new()), None))
+    }
+
+    fn name(&self) -> &'static str {
+        "4chan"
+    }
+
+    fn description(&self) -> &'static str {
+        "Searches 4chan"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    fn typing(&self) -> bool {
+        true
+    }
+
+    fn reconfigure(&self, settings: &Settings) -> config::Result<()> {
+        *plugin::lock(&self.allowed) = read_boards(settings)?;
+        Ok(())
     }
 
     fn is_match(&self, msg: &Message) -> bool {
-        msg.content().starts_with("!4c ")
+        self.commands.is_match(msg)
     }
 
-    fn handle(&mut self, msg: &Message, conn: &Connection) {
-        let content = msg.content();
-        let captures = self.regex.captures(&content);
-        if captures.is_some() {
-            let caps = captures.unwrap();
-            let board_name = caps.get(1).map_or("", |b| b.as_str());
-            let query = caps.get(2).map_or("", |q| q.as_str());
-
-            let empty_b = match clover::Board::new(self.client.to_owned(),
-                                                   board_name) {
-                Ok(b) => b,
-                Err(e) => {
-                    if e.description() == "Invalid board name"{
-                        conn.reply(msg, "That's not a board");
-                    }
-                    return
-                }
-            };
+    /// Comes before `4chan-image`, which would otherwise also answer a
+    /// search that contains an image link.
+    fn priority(&self) -> i32 {
+        1
+    }
+
+    fn consumes(&self, _msg: &Message) -> bool {
+        true
+    }
 
-            let board = self.boards.entry(board_name.to_string())
-                .or_insert(empty_b);
-            let _ = board.catalog().unwrap();
-            let threads = board.find_cached(query.to_string().trim())
-                .unwrap().iter()
-                .map(|t| format!("{} {}", t.url(), sub_or_com(t)))
-                .collect::<Vec<String>>().join("\n");
-            if threads.is_empty() {
-                conn.reply(msg, &format!("Found no matches for query {} in board {}",
-                                         query.to_string().trim(), board_name));
-            } else {
-                conn.reply(msg, &format!("Found matches for query {}:",
-                                         query.to_string().trim()));
-                conn.send(msg, &threads.to_string());
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+}
+
+impl FourchanPlugin {
+    /// Creates a `FourchanPlugin` from its `[plugins.4chan]` settings:
+    /// `boards`, the boards that may be searched. All may be if it is unset.
+    pub fn with_settings(settings: &Settings) -> config::Result<Box<Plugin>> {
+        Ok(Box::new(FourchanPlugin::with_boards(Box::new(Clover::new()),
+                                                read_boards(settings)?)))
+    }
+
+    /// Creates a `FourchanPlugin` that reads boards from `chan`, and may only
+    /// search those in `allowed`, if given.
+    pub fn with_boards(chan: Box<Chan>, allowed: Option<Vec<String>>)
+        -> FourchanPlugin {
+        FourchanPlugin {
+            chan: chan,
+            allowed: Mutex::new(allowed),
+            commands: Router::new()
+                .add(Command::new("4c")
+                     .arg("board", ArgKind::Word)
+                     .arg("query", ArgKind::Rest)
+                     .description("Searches the threads on a board"),
+                     FourchanPlugin::search)
+        }
+    }
+
+    fn search(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let board_name = args.str("board").unwrap();
+        let query = args.str("query").unwrap();
+        if let Some(ref allowed) = *plugin::lock(&self.allowed) {
+            if !allowed.iter().any(|b| b == board_name) {
+                conn.reply(msg, &format!("Searching /{}/ is not allowed here",
+                                         board_name))?;
+                return Ok(())
             }
         }
+        let threads = match self.chan.search(board_name, query) {
+            Ok(threads) => threads,
+            Err(LookupError::NoSuchBoard) => {
+                conn.reply(msg, "That's not a board")?;
+                return Ok(())
+            }
+            Err(LookupError::Failed(err)) => {
+                conn.reply(msg, &format!("Could not search /{}/ right now",
+                                         board_name))?;
+                return Err(lookup_failed(board_name, &err))
+            }
+        };
+        if threads.is_empty() {
+            conn.reply(msg, &format!("Found no matches for query {} in board {}",
+                                     query, board_name))?;
+        } else {
+            conn.reply(msg, &format!("Found matches for query {}:", query))?;
+            for thread in threads.iter().take(MAX_RESULT_EMBEDS) {
+                conn.send_embed(msg, &thread_embed(thread))?;
+            }
+            if threads.len() > MAX_RESULT_EMBEDS {
+                conn.send(msg, &format!("…and {} more",
+                                        threads.len() - MAX_RESULT_EMBEDS))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn failed<E: fmt::Debug>(err: E) -> LookupError {
+    LookupError::Failed(format!("{:?}", err))
+}
+
+fn lookup_failed(board: &str, err: &str) -> error::Error {
+    error::Error::Plugin(format!("Failed to read /{}/: {}", board, err))
+}
+
+fn read_boards(settings: &Settings) -> config::Result<Option<Vec<String>>> {
+    settings.check_keys(SETTINGS)?;
+    settings.str_list("boards")
+}
+
+fn thread_embed(thread: &Found) -> Embed {
+    let embed = Embed::new()
+        .title(&thread.title)
+        .url(&thread.url);
+    match thread.image {
+        Some(ref image) => embed.thumbnail(image),
+        None => embed
     }
 }
 
 fn sub_or_com(thread: &clover::Thread) -> String {
     let t = thread.clone();
     if t.topic.sub.is_empty() {
-        if t.topic.com.len() > 50 {
-            let mut abridged = t.topic.com;
-            abridged.truncate(50);
-            format!("{}...", abridged)
-        } else {
-            format!("{}", t.topic.com)
-        }
+        abridge(&t.topic.com)
     } else {
         t.topic.sub
     }
 }
+
+/// The first 50 characters of `com`, with "..." after them if there were
+/// more.
+fn abridge(com: &str) -> String {
+    if com.chars().count() > 50 {
+        format!("{}...", com.chars().take(50).collect::<String>())
+    } else {
+        com.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use std::sync::Arc;
+    use discord::model::ChannelId;
+    use ::bot::{Bot, Connection};
+    use ::executor::FullPolicy;
+    use ::plugin::Plugin;
+    use ::transport::{FakeTransport, test_connection, test_message as message};
+    use super::*;
+
+    /// Has two threads on /g/, and fails to read /down/.
+    struct Canned;
+
+    fn threads() -> Vec<Found> {
+        vec![
+            Found {
+                title: "rust general".to_string(),
+                url: "https://boards.4chan.org/g/thread/1".to_string(),
+                image: Some("https://i.4cdn.org/g/11.png".to_string())
+            },
+            Found {
+                title: "desktop thread".to_string(),
+                url: "https://boards.4chan.org/g/thread/2".to_string(),
+                image: None
+            }
+        ]
+    }
+
+    impl Chan for Canned {
+        fn search(&self, board: &str, query: &str)
+            -> ::std::result::Result<Vec<Found>, LookupError> {
+            match board {
+                "g" => Ok(threads().into_iter()
+                          .filter(|t| t.title.contains(query))
+                          .collect()),
+                "down" => Err(LookupError::Failed("timed out".to_string())),
+                _ => Err(LookupError::NoSuchBoard)
+            }
+        }
+
+        fn thread_of(&self, board: &str, image: &str)
+            -> ::std::result::Result<Option<String>, LookupError> {
+            if board != "g" { return Err(LookupError::NoSuchBoard) }
+            Ok(threads().into_iter()
+               .find(|t| t.image.as_ref().map_or(false, |i| i == image))
+               .map(|t| t.url))
+        }
+    }
+
+    fn search_plugin(allowed: Option<Vec<String>>)
+        -> (FourchanPlugin, Arc<FakeTransport>, Connection) {
+        let (fake, conn) = test_connection();
+        (FourchanPlugin::with_boards(Box::new(Canned), allowed), fake, conn)
+    }
+
+    #[test]
+    fn search_sends_an_embed_per_thread() {
+        let (plugin, fake, conn) = search_plugin(None);
+        plugin.handle(&message("!4c g rust"), &conn).unwrap();
+
+        assert_eq!(fake.sent()[0].1, "<@1> Found matches for query rust:");
+        let embeds = fake.embeds();
+        assert_eq!(embeds.len(), 1);
+        assert_eq!(embeds[0].1.title, Some("rust general".to_string()));
+        assert_eq!(embeds[0].1.thumbnail,
+                   Some("https://i.4cdn.org/g/11.png".to_string()));
+    }
+
+    #[test]
+    fn search_says_when_nothing_matches() {
+        let (plugin, fake, conn) = search_plugin(None);
+        plugin.handle(&message("!4c g haskell"), &conn).unwrap();
+        assert_eq!(fake.sent()[0].1,
+                   "<@1> Found no matches for query haskell in board g");
+        assert!(fake.embeds().is_empty());
+    }
+
+    #[test]
+    fn search_rejects_unknown_and_disallowed_boards() {
+        let (plugin, fake, conn) = search_plugin(Some(vec!["g".to_string(),
+                                                           "zz".to_string()]));
+        plugin.handle(&message("!4c zz rust"), &conn).unwrap();
+        plugin.handle(&message("!4c a rust"), &conn).unwrap();
+        let sent = fake.sent().into_iter().map(|(_, text)| text)
+            .collect::<Vec<String>>();
+        assert_eq!(sent, vec!["<@1> That's not a board",
+                              "<@1> Searching /a/ is not allowed here"]);
+    }
+
+    #[test]
+    fn a_failed_search_is_an_error() {
+        let (plugin, fake, conn) = search_plugin(None);
+        assert!(plugin.handle(&message("!4c down rust"), &conn).is_err());
+        assert_eq!(fake.sent()[0].1, "<@1> Could not search /down/ right now");
+    }
+
+    #[test]
+    fn image_links_are_traced_to_their_thread() {
+        let (fake, conn) = test_connection();
+        let plugin = FourchanImagePlugin::with_chan(Box::new(Canned));
+
+        let msg = message("look https://i.4cdn.org/g/11.png");
+        assert!(plugin.is_match(&msg));
+        plugin.handle(&msg, &conn).unwrap();
+        plugin.handle(&message("https://i.4cdn.org/g/12.png"), &conn).unwrap();
+        assert_eq!(fake.sent(), vec![
+            (ChannelId(1), "<@1> posted an image from \
+                            https://boards.4chan.org/g/thread/1".to_string())
+        ]);
+    }
+
+    #[test]
+    fn a_search_for_an_image_link_is_only_a_search() {
+        let fake = Arc::new(FakeTransport::new());
+        let mut bot = Bot::with_transport(fake);
+        // Without workers the messages stay queued where they were sent.
+        bot.set_pool(0, 10, FullPolicy::Drop);
+        let image = FourchanImagePlugin::with_chan(Box::new(Canned));
+        bot.register(Box::new(image));
+        bot.register(Box::new(FourchanPlugin::with_boards(Box::new(Canned),
+                                                          None)));
+        let queued = |bot: &Bot| {
+            bot.queue_stats().into_iter()
+                .filter(|&(name, _)| name.starts_with("4chan"))
+                .map(|(name, stats)| (name, stats.queued))
+                .collect::<Vec<(&'static str, usize)>>()
+        };
+
+        bot.dispatch(message("!4c g https://i.4cdn.org/g/11.png"));
+        assert_eq!(queued(&bot), vec![("4chan", 1), ("4chan-image", 0)]);
+        bot.dispatch(message("look https://i.4cdn.org/g/11.png"));
+        assert_eq!(queued(&bot), vec![("4chan", 1), ("4chan-image", 1)]);
+    }
+
+    fn repeat(s: &str, n: usize) -> String {
+        iter::repeat(s).take(n).collect()
+    }
+
+    #[test]
+    fn abridges_comments_on_characters() {
+        assert_eq!(abridge("short"), "short");
+        assert_eq!(abridge(&repeat("é", 60)), format!("{}...", repeat("é", 50)));
+        assert_eq!(abridge(&repeat("日", 50)), repeat("日", 50));
+    }
+}
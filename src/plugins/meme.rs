@@ -4,18 +4,26 @@ extern crate discord;
 extern crate rand;
 extern crate rustc_serialize;
 
-use std::collections::BTreeMap;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::sync::Mutex;
+use std::time::Duration;
 
-use chrono::{DateTime, Duration, UTC};
-use discord::model::UserId;
+use chrono::{DateTime, UTC};
 use plugins::rand::Rng;
 use ::bot::{Connection, Message};
-use ::plugin::Plugin;
+use ::command::{Args, ArgKind, Command, Router};
+use ::config::{self, ConfigError, Settings};
+use ::embed::Embed;
+use ::error::{Error, Result};
+use ::limit::RateLimit;
+use ::plugin::{self, Plugin};
 
 static FILE_PATH: &'static str = "memelist.csv";
+const DEFAULT_COOLDOWN_SECS: u64 = 60;
+/// The keys read from `[plugins.meme]`.
+pub const SETTINGS: &'static [&'static str] = &["path", "cooldown"];
 
 #[derive(Clone, RustcEncodable, RustcDecodable)]
 struct Meme {
@@ -25,100 +33,284 @@ struct Meme {
 }
 
 pub struct MemePlugin {
-    file: File,
-    memes: Vec<Meme>,
-    cooldown: Duration,
+    path: Mutex<String>,
+    // Where new memes are appended, or `None` to keep them in memory only.
+    file: Mutex<Option<File>>,
+    memes: Mutex<Vec<Meme>>,
+    cooldown: Mutex<Duration>,
     //ban_duration: Duration,
-    last_used_map: BTreeMap<UserId, DateTime<UTC>>,
-    last_meme: Option<Meme>,
+    last_meme: Mutex<Option<Meme>>,
+    commands: Router<MemePlugin>,
 }
 
 impl Plugin for MemePlugin {
     fn new() -> Box<Plugin> {
-        let file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(FILE_PATH)
-            .expect("Failed to open file");
-
-        let mut rdr = csv::Reader::from_file(FILE_PATH)
-            .expect("Failed to read file")
-            .has_headers(false);
-        let memes = rdr.decode()
-            .collect::<csv::Result<Vec<Meme>>>()
-            .expect("Failed to decode");
-
-        Box::new(MemePlugin {
-            file: file,
-            memes: memes,
-            cooldown: Duration::seconds(60),
-            //ban_duration: Duration::minutes(15),
-            last_used_map: BTreeMap::new(),
-            last_meme: None,
-        })
+        let cooldown = Duration::from_secs(DEFAULT_COOLDOWN_SECS);
+        let plugin = MemePlugin::open(FILE_PATH, cooldown);
+        Box::new(plugin.unwrap_or_else(|e| panic!("{}", e)))
+    }
+
+    fn name(&self) -> &'static str {
+        "meme"
+    }
+
+    fn description(&self) -> &'static str {
+        "Keeps a list of memes"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    /// One meme per user per `cooldown`. `!memeinfo` is not limited.
+    fn rate_limit(&self, command: Option<&Command>) -> Option<RateLimit> {
+        if !command.map_or(false, |c| c.name == "meme") { return None }
+        let cooldown = *plugin::lock(&self.cooldown);
+        if cooldown == Duration::from_secs(0) { return None }
+        Some(RateLimit::fixed_window(1, cooldown))
+    }
+
+    /// Reopens the meme list if `path` changed. The old file is kept if the
+    /// new one cannot be read.
+    fn reconfigure(&self, settings: &Settings) -> config::Result<()> {
+        let (path, cooldown) = read_settings(settings)?;
+        let mut current = plugin::lock(&self.path);
+        if path != *current {
+            let (file, memes) = read_memes(&path)
+                .map_err(|e| ConfigError::new(&settings.key("path"), &e))?;
+            let mut old = plugin::lock(&self.file);
+            if let Some(Err(err)) = old.as_ref().map(|f| f.sync_all()) {
+                println!("[Warning] Failed to flush {}: {}", *current, err);
+            }
+            *old = Some(file);
+            *plugin::lock(&self.memes) = memes;
+            *current = path;
+        }
+        *plugin::lock(&self.cooldown) = cooldown;
+        Ok(())
     }
 
     fn is_match(&self, msg: &Message) -> bool {
-        let content = msg.content();
-        content.starts_with("!meme") ||
-            content == "!info"
-    }
-
-    fn handle(&mut self, msg: &Message, conn: &Connection) {
-        let content = msg.content();
-        // Get a meme
-        if content == "!meme" {
-            if self.is_banned(msg, conn) { return };
-            let meme = rand::thread_rng().choose(&self.memes).unwrap();
-            self.last_meme = Some(meme.clone());
-            conn.reply(msg, &meme.content);
+        self.commands.is_match(msg)
+    }
+
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+
+    fn on_shutdown(&self, _conn: &Connection) {
+        if let Some(Err(err)) = plugin::lock(&self.file).as_ref()
+            .map(|f| f.sync_all()) {
+            println!("[Warning] Failed to flush {}: {}",
+                     *plugin::lock(&self.path), err);
         }
+    }
+}
 
-        // Add a meme
-        else if content.starts_with("!meme ") {
-            if self.is_banned(msg, conn) { return };
-            let meme = Meme {
-                date: UTC::now(),
-                author: msg.author().name,
-                content: String::from(&content[6..]),
-            };
-            let mut wtr = csv::Writer::from_memory();
-            wtr.encode(meme.clone()).expect("Failed to encode");
-            self.file.write_all(wtr.as_bytes())
-                .expect("Failed to write to file");
-            self.memes.push(meme.clone());
-            conn.reply(msg, &format!("{} is now a meme", meme.content));
+impl MemePlugin {
+    /// Creates a `MemePlugin` from its `[plugins.meme]` settings: `path`,
+    /// the CSV file memes are kept in, and `cooldown`, the seconds a user
+    /// must wait between memes.
+    pub fn with_settings(settings: &Settings) -> config::Result<Box<Plugin>> {
+        let (path, cooldown) = read_settings(settings)?;
+        match MemePlugin::open(&path, cooldown) {
+            Ok(plugin) => Ok(Box::new(plugin)),
+            Err(err) => Err(ConfigError::new(&settings.key("path"), &err))
+        }
+    }
+
+    fn open(path: &str, cooldown: Duration)
+        -> ::std::result::Result<MemePlugin, String> {
+        let (file, memes) = read_memes(path)?;
+        Ok(MemePlugin::with_memes(path, Some(file), memes, cooldown))
+    }
+
+    /// Creates a `MemePlugin` that starts with `memes` and appends new ones
+    /// to `file`, the meme list at `path`. Without a file they are only kept
+    /// in memory.
+    fn with_memes(path: &str, file: Option<File>, memes: Vec<Meme>,
+                  cooldown: Duration) -> MemePlugin {
+        MemePlugin {
+            path: Mutex::new(path.to_string()),
+            file: Mutex::new(file),
+            memes: Mutex::new(memes),
+            cooldown: Mutex::new(cooldown),
+            //ban_duration: Duration::minutes(15),
+            last_meme: Mutex::new(None),
+            commands: Router::new()
+                .add(Command::new("meme")
+                     .optional("meme", ArgKind::Rest)
+                     .description("Posts a random meme, or adds a new one"),
+                     MemePlugin::meme)
+                .add(Command::new("memeinfo")
+                     .alias("info")
+                     .description("Shows who added the last meme and when"),
+                     MemePlugin::info),
         }
+    }
+
+    fn meme(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        match args.str("meme") {
+            // Get a meme
+            None => {
+                let meme = match rand::thread_rng()
+                    .choose(&plugin::lock(&self.memes)) {
+                    Some(meme) => meme.clone(),
+                    None => {
+                        conn.reply(msg, &format!("There are no memes yet. \
+                                                  Add one with `{}meme <text>`",
+                                                 msg.prefix()))?;
+                        return Ok(())
+                    }
+                };
+                *plugin::lock(&self.last_meme) = Some(meme.clone());
+                conn.reply(msg, &meme.content)?;
+            }
 
-        // Get meme info
-        else if content == "!memeinfo" || content == "!info" {
-            match self.last_meme.clone() {
-                None => return,
-                Some(m) => {
-                    conn.reply(msg, &format!(
-                        "This meme was added by {} at {}",
-                        m.author, m.date.to_rfc2822()));
+            // Add a meme
+            Some(content) => {
+                let meme = Meme {
+                    date: UTC::now(),
+                    author: msg.author().name,
+                    content: content.to_string(),
+                };
+                let mut wtr = csv::Writer::from_memory();
+                let saved = wtr.encode(meme.clone())
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| match *plugin::lock(&self.file) {
+                        Some(ref mut file) => file.write_all(wtr.as_bytes())
+                            .map_err(|e| e.to_string()),
+                        None => Ok(())
+                    });
+                if let Err(err) = saved {
+                    conn.reply(msg, "Failed to save the meme")?;
+                    return Err(Error::Plugin(format!(
+                        "Failed to write to {}: {}", *plugin::lock(&self.path),
+                        err)))
                 }
+                plugin::lock(&self.memes).push(meme.clone());
+                conn.reply(msg, &format!("{} is now a meme", meme.content))?;
             }
         }
+        Ok(())
+    }
+
+    fn info(&self, _args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        if let Some(m) = plugin::lock(&self.last_meme).clone() {
+            let embed = Embed::new()
+                .description(&m.content)
+                .field("Author", &m.author, true)
+                .field("Added", &m.date.to_rfc2822(), true)
+                .timestamp(m.date);
+            conn.reply_embed(msg, &embed)?;
+        }
+        Ok(())
     }
 }
 
-impl MemePlugin {
-    fn is_banned(&mut self, msg: &Message, conn: &Connection) -> bool {
-        let now = UTC::now();
-        let author = msg.author();
-        if self.last_used_map.contains_key(&author.id) {
-            if now.signed_duration_since(
-                *self.last_used_map
-                .get(&author.id)
-                .unwrap()) <= self.cooldown {
-                conn.reply(msg, "whoa there, slow down desu senpai");
-                return true
-            }
+/// Reads the `path` and `cooldown` settings.
+fn read_settings(settings: &Settings) -> config::Result<(String, Duration)> {
+    settings.check_keys(SETTINGS)?;
+    let path = settings.str("path")?.unwrap_or_else(|| FILE_PATH.to_string());
+    let cooldown = match settings.int("cooldown")? {
+        Some(secs) if secs < 0 => {
+            return Err(ConfigError::new(&settings.key("cooldown"),
+                                        "must not be negative"))
         }
-        self.last_used_map.insert(author.id, now);
-        false
+        Some(secs) => secs as u64,
+        None => DEFAULT_COOLDOWN_SECS
+    };
+    Ok((path, Duration::from_secs(cooldown)))
+}
+
+/// Opens the meme list at `path` for appending, creating it if need be, and
+/// reads the memes already in it.
+fn read_memes(path: &str)
+    -> ::std::result::Result<(File, Vec<Meme>), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+
+    let mut rdr = csv::Reader::from_file(path)
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?
+        .has_headers(false);
+    let memes = rdr.decode()
+        .collect::<csv::Result<Vec<Meme>>>()
+        .map_err(|e| format!("Failed to decode {}: {}", path, e))?;
+    Ok((file, memes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::UTC;
+    use ::bot::Connection;
+    use ::plugin::Plugin;
+    use ::transport::{FakeTransport, test_connection, test_message as message};
+    use super::{Meme, MemePlugin};
+
+    fn setup(memes: Vec<&str>) -> (MemePlugin, Arc<FakeTransport>, Connection) {
+        let memes = memes.into_iter()
+            .map(|content| Meme {
+                date: UTC::now(),
+                author: "someone".to_string(),
+                content: content.to_string()
+            })
+            .collect();
+        let (fake, conn) = test_connection();
+        let plugin = MemePlugin::with_memes("memes", None, memes,
+                                            Duration::from_secs(0));
+        (plugin, fake, conn)
+    }
+
+    #[test]
+    fn posts_a_meme_and_tells_who_added_it() {
+        let (plugin, fake, conn) = setup(vec!["doge"]);
+        plugin.handle(&message("!meme"), &conn).unwrap();
+        plugin.handle(&message("!info"), &conn).unwrap();
+
+        assert_eq!(fake.sent()[0].1, "<@1> doge");
+        let embeds = fake.embeds();
+        assert_eq!(embeds.len(), 1);
+        assert_eq!(embeds[0].1.description, Some("doge".to_string()));
+        assert_eq!(embeds[0].1.fields[0].value, "someone");
+    }
+
+    #[test]
+    fn adds_a_meme() {
+        let (plugin, fake, conn) = setup(vec![]);
+        plugin.handle(&message("!meme such wow"), &conn).unwrap();
+        plugin.handle(&message("!meme"), &conn).unwrap();
+
+        let sent = fake.sent().into_iter().map(|(_, text)| text)
+            .collect::<Vec<String>>();
+        assert_eq!(sent, vec!["<@1> such wow is now a meme",
+                              "<@1> such wow"]);
+    }
+
+    #[test]
+    fn only_memes_are_rate_limited() {
+        let (plugin, _, _) = setup(vec![]);
+        *plugin.cooldown.lock().unwrap() = Duration::from_secs(60);
+        let commands = plugin.commands();
+        let meme = commands.iter().find(|c| c.name == "meme");
+        let info = commands.iter().find(|c| c.name == "memeinfo");
+        assert!(meme.is_some() && info.is_some());
+        assert!(plugin.rate_limit(meme).is_some());
+        assert!(plugin.rate_limit(info).is_none());
+        assert!(plugin.rate_limit(None).is_none());
+    }
+
+    #[test]
+    fn says_when_there_are_no_memes() {
+        let (plugin, fake, conn) = setup(vec![]);
+        plugin.handle(&message("!meme"), &conn).unwrap();
+        assert!(fake.sent()[0].1.starts_with("<@1> There are no memes yet"));
     }
 }
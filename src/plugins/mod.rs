@@ -7,3 +7,46 @@ pub mod bully;
 pub mod fourchan;
 pub mod meme;
 pub mod anime;
+
+use ::config::{self, ConfigError, Settings};
+use ::plugin::Plugin;
+
+/// The settings of plugins that have none.
+const NO_SETTINGS: &'static [&'static str] = &[];
+
+/// The keys the plugin called `name` reads from its `[plugins.<name>]` table,
+/// or `None` if there is no such plugin.
+pub fn settings_keys(name: &str) -> Option<&'static [&'static str]> {
+    match name {
+        "bully" | "hug" | "4chan-image" => Some(NO_SETTINGS),
+        "meme" => Some(meme::SETTINGS),
+        "4chan" => Some(fourchan::SETTINGS),
+        "anime" => Some(anime::SETTINGS),
+        _ => None
+    }
+}
+
+/// Creates the plugin called `name` from its settings. The names are those in
+/// `config::DEFAULT_PLUGINS`.
+pub fn build(name: &str, settings: &Settings) -> config::Result<Box<Plugin>> {
+    match name {
+        "bully" => {
+            settings.check_keys(NO_SETTINGS)?;
+            Ok(bully::BullyPlugin::new())
+        }
+        "hug" => {
+            settings.check_keys(NO_SETTINGS)?;
+            Ok(bully::HugPlugin::new())
+        }
+        "meme" => meme::MemePlugin::with_settings(settings),
+        "4chan-image" => {
+            settings.check_keys(NO_SETTINGS)?;
+            Ok(fourchan::FourchanImagePlugin::new())
+        }
+        "4chan" => fourchan::FourchanPlugin::with_settings(settings),
+        "anime" => anime::AnimePlugin::with_settings(settings),
+        _ => Err(ConfigError::new("plugins.enabled",
+                                  &format!("there is no plugin called `{}`",
+                                           name)))
+    }
+}
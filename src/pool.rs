@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Job = Box<FnOnce() + Send>;
+
+/// A snapshot of how saturated a `WorkerPool` is.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub active: usize,
+    pub queued: usize,
+    pub completed: usize
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    active: usize,
+    shutdown: bool
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    // Signalled when a job is queued or the pool shuts down.
+    available: Condvar,
+    // Signalled when the queue is empty and no job is running.
+    idle: Condvar,
+    completed: AtomicUsize
+}
+
+/// A fixed number of worker threads fed from a queue. The workers exit once
+/// the pool is dropped and the queue has drained.
+///
+/// The queue has no bound of its own, so that `execute` never blocks the
+/// gateway thread. Plugins bound it instead: each `PluginExecutor` keeps at
+/// most one drain job in it.
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    workers: usize
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> WorkerPool {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                active: 0,
+                shutdown: false
+            }),
+            available: Condvar::new(),
+            idle: Condvar::new(),
+            completed: AtomicUsize::new(0)
+        });
+
+        for i in 0..workers {
+            let shared_1 = shared.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || work(shared_1))
+                .expect("Failed to spawn worker");
+        }
+
+        WorkerPool {
+            shared: shared,
+            workers: workers
+        }
+    }
+
+    /// Queues a job to run on the next free worker.
+    pub fn execute<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.jobs.push_back(Box::new(job));
+        self.shared.available.notify_one();
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let queue = self.shared.queue.lock().unwrap();
+        PoolStats {
+            workers: self.workers,
+            active: queue.active,
+            queued: queue.jobs.len(),
+            completed: self.shared.completed.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Blocks until every queued job has run.
+    pub fn wait_idle(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        while queue.active > 0 || !queue.jobs.is_empty() {
+            queue = self.shared.idle.wait(queue).unwrap();
+        }
+    }
+
+    /// Blocks until every queued job has run or `timeout` has passed.
+    /// Returns `false` on timeout.
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.queue.lock().unwrap();
+        while queue.active > 0 || !queue.jobs.is_empty() {
+            let now = Instant::now();
+            if now >= deadline { return false }
+            queue = self.shared.idle.wait_timeout(queue, deadline - now)
+                .unwrap().0;
+        }
+        true
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+    }
+}
+
+fn work(shared: Arc<Shared>) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some(job) = queue.jobs.pop_front() {
+                    queue.active += 1;
+                    break job
+                }
+                if queue.shutdown { return }
+                queue = shared.available.wait(queue).unwrap();
+            }
+        };
+
+        // A panicking job must not take the worker down with it.
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            println!("[Error] Job panicked on {}",
+                     thread::current().name().unwrap_or("worker"));
+        }
+
+        let mut queue = shared.queue.lock().unwrap();
+        queue.active -= 1;
+        shared.completed.fetch_add(1, Ordering::SeqCst);
+        if queue.active == 0 && queue.jobs.is_empty() {
+            shared.idle.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::WorkerPool;
+
+    #[test]
+    fn runs_every_job() {
+        let pool = WorkerPool::new(3);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..20 {
+            let ran = ran.clone();
+            pool.execute(move || ran.lock().unwrap().push(i));
+        }
+        pool.wait_idle();
+        let mut ran = ran.lock().unwrap().clone();
+        ran.sort();
+        assert_eq!(ran, (0..20).collect::<Vec<i32>>());
+        assert_eq!(pool.stats().completed, 20);
+    }
+
+    #[test]
+    fn execute_does_not_wait_for_a_busy_worker() {
+        let pool = WorkerPool::new(1);
+        let (release, wait) = mpsc::channel::<()>();
+        pool.execute(move || { let _ = wait.recv(); });
+        // With the only worker held up, these just queue.
+        for _ in 0..100 {
+            pool.execute(|| {});
+        }
+        assert!(!pool.wait_idle_timeout(Duration::from_millis(10)));
+        release.send(()).unwrap();
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+        assert_eq!(pool.stats().completed, 101);
+    }
+
+    #[test]
+    fn a_panicking_job_does_not_stop_its_worker() {
+        let pool = WorkerPool::new(1);
+        let ran = Arc::new(Mutex::new(false));
+        pool.execute(|| panic!("job failed"));
+        let ran_1 = ran.clone();
+        pool.execute(move || *ran_1.lock().unwrap() = true);
+        pool.wait_idle();
+        assert!(*ran.lock().unwrap());
+    }
+}
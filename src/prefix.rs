@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use discord::model::{ServerId, UserId};
+use discord::model::permissions::MANAGE_SERVER;
+use access::Access;
+use bot::{Connection, Message};
+use command::{Args, ArgKind, Command, Router};
+use error::Result;
+use plugin::Plugin;
+use store;
+
+/// The prefix used where no other has been set.
+pub const DEFAULT_PREFIX: &'static str = "!";
+/// Where per-server prefixes are kept between runs.
+const FILE_PATH: &'static str = "prefixes.json";
+/// The longest prefix a server may set.
+const MAX_PREFIX_LEN: usize = 8;
+
+/// `Prefixes` decides what a message must start with to be a command: the
+/// server's own prefix if it has set one, the global prefix otherwise, or an
+/// @mention of the bot in place of either, at the very start of the message.
+/// Per-server prefixes are saved to `prefixes.json` whenever they change.
+pub struct Prefixes {
+    default: RwLock<String>,
+    servers: RwLock<HashMap<ServerId, String>>,
+    user: RwLock<Option<UserId>>,
+    path: PathBuf
+}
+
+impl Prefixes {
+    /// Loads the per-server prefixes saved by an earlier run, if any.
+    pub fn load() -> Prefixes {
+        Prefixes::load_from(PathBuf::from(FILE_PATH))
+    }
+
+    /// Like `load`, but keeps the prefixes at `path`.
+    pub fn load_from(path: PathBuf) -> Prefixes {
+        let servers = match store::load(&path, "prefix") {
+            Ok(servers) => servers,
+            Err(err) => {
+                println!("[Warning] Failed to load {}: {}", path.display(),
+                         err);
+                HashMap::new()
+            }
+        };
+        Prefixes {
+            default: RwLock::new(DEFAULT_PREFIX.to_string()),
+            servers: RwLock::new(servers),
+            user: RwLock::new(None),
+            path: path
+        }
+    }
+
+    /// The prefix used in servers that have not set their own.
+    pub fn default(&self) -> String {
+        self.default.read().unwrap().clone()
+    }
+
+    pub fn set_default(&self, prefix: &str) {
+        *self.default.write().unwrap() = prefix.to_string();
+    }
+
+    /// The prefix in effect in a server, or in private messages for `None`.
+    pub fn get(&self, server: Option<ServerId>) -> String {
+        server.and_then(|s| self.servers.read().unwrap().get(&s).cloned())
+            .unwrap_or_else(|| self.default())
+    }
+
+    /// Sets a server's prefix, or goes back to the global one for `None`,
+    /// and saves the change.
+    pub fn set(&self, server: ServerId, prefix: Option<&str>) -> io::Result<()> {
+        let mut servers = self.servers.write().unwrap();
+        match prefix {
+            Some(prefix) => servers.insert(server, prefix.to_string()),
+            None => servers.remove(&server)
+        };
+        store::save(&self.path, &servers)
+    }
+
+    /// Sets the bot's own user, so that mentioning it works as a prefix.
+    pub fn set_user(&self, user: UserId) {
+        *self.user.write().unwrap() = Some(user);
+    }
+
+    /// Tells the message which prefixes it may start with.
+    pub fn apply(&self, msg: &mut Message) {
+        let prefix = self.get(msg.server_id());
+        msg.set_prefix(prefix, *self.user.read().unwrap());
+    }
+}
+
+/// The built-in `!prefix` command, which shows the prefix in a server and
+/// lets its admins change it.
+pub struct PrefixPlugin {
+    prefixes: Arc<Prefixes>,
+    commands: Router<PrefixPlugin>
+}
+
+impl PrefixPlugin {
+    pub fn with_prefixes(prefixes: Arc<Prefixes>) -> PrefixPlugin {
+        PrefixPlugin {
+            prefixes: prefixes,
+            commands: Router::new()
+                .add(Command::new("prefix")
+                     .description("Shows the command prefix"),
+                     PrefixPlugin::show)
+                .add(Command::new("prefix")
+                     .arg("reset", ArgKind::Literal("reset"))
+                     .description("Goes back to the default prefix")
+                     .access(Access::Permission(MANAGE_SERVER)),
+                     PrefixPlugin::reset)
+                .add(Command::new("prefix")
+                     .arg("prefix", ArgKind::Word)
+                     .description("Changes the command prefix in this server")
+                     .access(Access::Permission(MANAGE_SERVER)),
+                     PrefixPlugin::change)
+        }
+    }
+
+    fn show(&self, _args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        conn.reply(msg, &format!("Commands here start with `{}`",
+                                 msg.prefix()))?;
+        Ok(())
+    }
+
+    fn reset(&self, _args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        self.update(msg, conn, None)
+    }
+
+    fn change(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let prefix = args.str("prefix").unwrap();
+        if prefix.chars().count() > MAX_PREFIX_LEN {
+            conn.reply(msg, &format!("A prefix can be at most {} characters",
+                                     MAX_PREFIX_LEN))?;
+            return Ok(())
+        }
+        self.update(msg, conn, Some(prefix))
+    }
+
+    fn update(&self, msg: &Message, conn: &Connection, prefix: Option<&str>)
+        -> Result<()> {
+        let server = match msg.server_id() {
+            Some(server) => server,
+            None => {
+                conn.reply(msg, "The prefix can only be changed in a server")?;
+                return Ok(())
+            }
+        };
+        if let Err(err) = self.prefixes.set(server, prefix) {
+            println!("[Error] Failed to save {}: {}",
+                     self.prefixes.path.display(), err);
+        }
+        conn.reply(msg, &format!("Commands here now start with `{}`",
+                                 self.prefixes.get(Some(server))))?;
+        Ok(())
+    }
+}
+
+impl Plugin for PrefixPlugin {
+    /// Creates a `PrefixPlugin` with prefixes of its own. Use the one the
+    /// `Bot` registers instead.
+    fn new() -> Box<Plugin> {
+        Box::new(PrefixPlugin::with_prefixes(Arc::new(Prefixes::load())))
+    }
+
+    fn name(&self) -> &'static str {
+        "prefix"
+    }
+
+    fn description(&self) -> &'static str {
+        "Changes what commands start with"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    fn is_match(&self, msg: &Message) -> bool {
+        self.commands.is_match(msg)
+    }
+
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use discord::model::{ChannelId, ServerId, UserId};
+    use bot::Message;
+    use plugin::Plugin;
+    use store;
+    use transport::{fake_message, fake_user, test_connection, test_message};
+    use super::{PrefixPlugin, Prefixes};
+
+    fn in_server(content: &str) -> Message {
+        let msg = fake_message(ChannelId(1), fake_user(1, "user"), content);
+        Message::with_server(msg, Some(ServerId(5)))
+    }
+
+    #[test]
+    fn commands_follow_the_prefix_or_a_mention_of_the_bot() {
+        let command = |content: &str| {
+            let mut msg = test_message(content);
+            msg.set_prefix("?".to_string(), Some(UserId(9)));
+            msg.command()
+        };
+        assert_eq!(command("?ping"), Some("ping".to_string()));
+        assert_eq!(command("<@9> ping"), Some("ping".to_string()));
+        assert_eq!(command("<@!9>ping"), Some("ping".to_string()));
+        assert_eq!(command("!ping"), None);
+        assert_eq!(command("<@8> ping"), None);
+        assert_eq!(command("hey <@9> ping"), None);
+    }
+
+    #[test]
+    fn each_server_has_its_own_prefix() {
+        let path = store::test_path("prefixes-own.json");
+        let prefixes = Prefixes::load_from(path);
+        prefixes.set(ServerId(5), Some("?")).unwrap();
+
+        let mut here = in_server("?ping");
+        prefixes.apply(&mut here);
+        assert_eq!(here.command(), Some("ping".to_string()));
+        let mut elsewhere = test_message("?ping");
+        prefixes.apply(&mut elsewhere);
+        assert_eq!(elsewhere.command(), None);
+    }
+
+    #[test]
+    fn a_changed_prefix_is_kept_between_runs() {
+        let path = store::test_path("prefixes-kept.json");
+        let prefixes = Arc::new(Prefixes::load_from(path.clone()));
+        let plugin = PrefixPlugin::with_prefixes(prefixes.clone());
+        let (fake, conn) = test_connection();
+        plugin.handle(&in_server("!prefix ?"), &conn).unwrap();
+        assert_eq!(fake.sent()[0].1, "<@1> Commands here now start with `?`");
+
+        let reloaded = Prefixes::load_from(path.clone());
+        assert_eq!(reloaded.get(Some(ServerId(5))), "?");
+        assert_eq!(reloaded.get(Some(ServerId(6))), "!");
+
+        plugin.handle(&in_server("!prefix reset"), &conn).unwrap();
+        assert_eq!(Prefixes::load_from(path).get(Some(ServerId(5))), "!");
+    }
+}
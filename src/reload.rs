@@ -0,0 +1,426 @@
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use discord::model::UserId;
+use access::Access;
+use bot::{Connection, Message};
+use command::{Args, Command, Router};
+use config::{self, Config, Logging, Settings, Source};
+use error::Result;
+use executor::{self, PluginExecutor};
+use plugin::{self, Plugin};
+use prefix::{Prefixes, DEFAULT_PREFIX};
+
+/// Creates an enabled plugin from its name and settings, as
+/// `plugins::build` does.
+pub type Build = fn(&str, &Settings) -> config::Result<Box<Plugin>>;
+
+/// What a reload changed.
+#[derive(Clone, Debug, Default)]
+pub struct Changes {
+    pub enabled: Vec<String>,
+    pub disabled: Vec<String>,
+    pub reconfigured: Vec<String>
+}
+
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts = [("enabled", &self.enabled),
+                     ("disabled", &self.disabled),
+                     ("reconfigured", &self.reconfigured)];
+        let parts = parts.iter()
+            .filter(|&&(_, names)| !names.is_empty())
+            .map(|&(what, names)| format!("{} {}", what, names.join(", ")))
+            .collect::<Vec<String>>();
+        if parts.is_empty() {
+            f.write_str("no plugins changed")
+        } else {
+            f.write_str(&parts.join("; "))
+        }
+    }
+}
+
+/// `Reload` applies a config to a running `Bot`, and applies it again when
+/// the file changes. Only the plugins the config enables are managed here:
+/// the built-in ones and any registered by hand are left alone.
+///
+/// A config is applied in full or not at all. New plugins are built and
+/// changed settings pushed through `Plugin::reconfigure` before the registry
+/// is touched, and plugins that were already reconfigured get their old
+/// settings back if a later one fails. The enabled plugins are kept in the
+/// order the config lists them, after the others of the same priority.
+pub struct Reload {
+    source: Source,
+    build: Build,
+    // The config last applied, or `None` before the first.
+    current: Mutex<Option<Config>>,
+    plugins: Weak<RwLock<Vec<PluginExecutor>>>,
+    prefixes: Arc<Prefixes>,
+    logging: Arc<RwLock<Logging>>,
+    owners: Arc<RwLock<Vec<UserId>>>,
+    conn: Connection
+}
+
+impl Reload {
+    pub fn new(source: Source, build: Build,
+               plugins: Weak<RwLock<Vec<PluginExecutor>>>,
+               prefixes: Arc<Prefixes>, logging: Arc<RwLock<Logging>>,
+               owners: Arc<RwLock<Vec<UserId>>>, conn: Connection) -> Reload {
+        Reload {
+            source: source,
+            build: build,
+            current: Mutex::new(None),
+            plugins: plugins,
+            prefixes: prefixes,
+            logging: logging,
+            owners: owners,
+            conn: conn
+        }
+    }
+
+    /// Reads the config from its file again and applies it. Nothing changes
+    /// if it is invalid.
+    pub fn reload(&self) -> config::Result<Changes> {
+        let config = self.source.load()?;
+        self.apply(config)
+    }
+
+    /// Applies a config that has already been validated.
+    pub fn apply(&self, config: Config) -> config::Result<Changes> {
+        let mut current = plugin::lock(&self.current);
+        let old = current.clone().unwrap_or_else(|| {
+            let mut config = Config::default();
+            config.plugins.clear();
+            config
+        });
+        let plugins = match self.plugins.upgrade() {
+            Some(plugins) => plugins,
+            None => return Ok(Changes::default())
+        };
+        let mut changes = Changes::default();
+
+        let mut added = Vec::new();
+        for name in &config.plugins {
+            if old.plugins.contains(name) { continue }
+            added.push((self.build)(name, &config.settings(name))?);
+            changes.enabled.push(name.clone());
+        }
+
+        {
+            let registry = plugins.read().unwrap();
+            for name in &config.plugins {
+                let settings = config.settings(name);
+                if !old.plugins.contains(name) || settings == old.settings(name) {
+                    continue
+                }
+                let result = match find(&registry, name) {
+                    Some(e) => e.plugin().reconfigure(&settings),
+                    None => continue
+                };
+                if let Err(err) = result {
+                    for name in &changes.reconfigured {
+                        restore(&registry, name, &old.settings(name));
+                    }
+                    return Err(err)
+                }
+                changes.reconfigured.push(name.clone());
+            }
+        }
+
+        let removed = {
+            let mut registry = plugins.write().unwrap();
+            let mut unmanaged = Vec::new();
+            let mut kept = Vec::new();
+            let mut removed = Vec::new();
+            for e in mem::replace(&mut *registry, Vec::new()) {
+                let name = e.plugin().name().to_string();
+                if !old.plugins.contains(&name) {
+                    unmanaged.push(e);
+                } else if config.plugins.contains(&name) {
+                    kept.push(e);
+                } else {
+                    removed.push(e);
+                }
+            }
+
+            // Rebuilt in config order, so that enabling a plugin or moving
+            // it in the list takes effect as it would on a restart.
+            *registry = unmanaged;
+            let mut added = added.into_iter();
+            for name in &config.plugins {
+                let e = if old.plugins.contains(name) {
+                    match kept.iter().position(|e| e.plugin().name() == name) {
+                        Some(i) => kept.remove(i),
+                        None => continue
+                    }
+                } else {
+                    match added.next() {
+                        Some(plugin) => PluginExecutor::new(plugin),
+                        None => continue
+                    }
+                };
+                executor::insert(&mut registry, e);
+            }
+            removed
+        };
+        for e in removed {
+            changes.disabled.push(e.plugin().name().to_string());
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                e.plugin().on_shutdown(&self.conn);
+            }));
+            if result.is_err() {
+                println!("[Error] {} panicked while shutting down",
+                         e.plugin().name());
+            }
+        }
+
+        self.prefixes.set_default(config.prefix.as_ref()
+                                  .map_or(DEFAULT_PREFIX, |p| p.as_str()));
+        *self.logging.write().unwrap() = config.logging.clone();
+        *self.owners.write().unwrap() = config.owners.clone();
+        if current.is_some() && config.token != old.token {
+            println!("[Warning] The new token is only used after a restart");
+        }
+        if current.is_some() && config.runtime != old.runtime {
+            println!("[Warning] The new [runtime] settings are only used after \
+                      a restart");
+        }
+        *current = Some(config);
+        Ok(changes)
+    }
+}
+
+fn find<'a>(registry: &'a [PluginExecutor], name: &str)
+    -> Option<&'a PluginExecutor> {
+    registry.iter().find(|e| e.plugin().name() == name)
+}
+
+/// Gives a plugin back the settings it had before a failed reload.
+fn restore(registry: &[PluginExecutor], name: &str, settings: &Settings) {
+    if let Some(e) = find(registry, name) {
+        if let Err(err) = e.plugin().reconfigure(settings) {
+            println!("[Error] Failed to restore the settings of {}: {}",
+                     name, err);
+        }
+    }
+}
+
+/// The built-in `!reload` command, which lets the bot's owners reload the
+/// config without a restart.
+pub struct ReloadPlugin {
+    reload: Weak<Reload>,
+    commands: Router<ReloadPlugin>
+}
+
+impl ReloadPlugin {
+    pub fn with_reload(reload: Weak<Reload>) -> ReloadPlugin {
+        ReloadPlugin {
+            reload: reload,
+            commands: Router::new()
+                .add(Command::new("reload")
+                     .description("Reads the config file again")
+                     .access(Access::BotOwner),
+                     ReloadPlugin::reload)
+        }
+    }
+
+    fn reload(&self, _args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let reload = match self.reload.upgrade() {
+            Some(reload) => reload,
+            None => return Ok(())
+        };
+        match reload.reload() {
+            Ok(changes) => {
+                println!("[Reload] {}", changes);
+                conn.reply(msg, &format!("Reloaded the config: {}", changes))?;
+            }
+            Err(err) => {
+                println!("[Error] Config not reloaded: {}", err);
+                conn.reply(msg, &format!("The config was not reloaded: {}",
+                                         err))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for ReloadPlugin {
+    /// Creates a `ReloadPlugin` with nothing to reload. Use the one
+    /// `Bot::configure` registers instead.
+    fn new() -> Box<Plugin> {
+        Box::new(ReloadPlugin::with_reload(Weak::new()))
+    }
+
+    fn name(&self) -> &'static str {
+        "reload"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reloads the config"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    fn is_match(&self, msg: &Message) -> bool {
+        self.commands.is_match(msg)
+    }
+
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, RwLock};
+    use bot::{Connection, Message};
+    use config::{self, Config, ConfigError, Logging, Settings, Source};
+    use error::Result;
+    use executor::PluginExecutor;
+    use plugin::Plugin;
+    use prefix::Prefixes;
+    use store;
+    use transport::{test_connection, test_message};
+    use super::{find, Reload};
+
+    /// Stands in for any plugin, and replies to everything with its
+    /// settings. It can't be built with `path = "unbuildable"`, or
+    /// reconfigured with `quality = "bad"`.
+    struct Fake {
+        name: &'static str,
+        settings: Mutex<Settings>
+    }
+
+    impl Plugin for Fake {
+        fn new() -> Box<Plugin> {
+            build("hug", &Settings::empty("hug")).unwrap()
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn reconfigure(&self, settings: &Settings) -> config::Result<()> {
+            if settings.str("quality")? == Some("bad".to_string()) {
+                return Err(ConfigError::new(&settings.key("quality"), "bad"))
+            }
+            *self.settings.lock().unwrap() = settings.clone();
+            Ok(())
+        }
+
+        fn is_match(&self, _msg: &Message) -> bool {
+            true
+        }
+
+        fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+            let settings = self.settings.lock().unwrap();
+            conn.send(msg, &format!("{:?} {:?}",
+                                    settings.int("cooldown").unwrap(),
+                                    settings.str("quality").unwrap()))?;
+            Ok(())
+        }
+    }
+
+    fn build(name: &str, settings: &Settings)
+        -> config::Result<Box<Plugin>> {
+        if settings.str("path")? == Some("unbuildable".to_string()) {
+            return Err(ConfigError::new(&settings.key("path"), "unbuildable"))
+        }
+        let name = *["bully", "hug", "meme", "anime"].iter()
+            .find(|&&n| n == name).unwrap();
+        Ok(Box::new(Fake { name: name, settings: Mutex::new(settings.clone()) }))
+    }
+
+    struct Setup {
+        reload: Reload,
+        plugins: Arc<RwLock<Vec<PluginExecutor>>>,
+        prefixes: Arc<Prefixes>
+    }
+
+    impl Setup {
+        fn new(name: &str) -> Setup {
+            let plugins = Arc::new(RwLock::new(Vec::new()));
+            let path = store::test_path(&format!("prefixes-{}.json", name));
+            let prefixes = Arc::new(Prefixes::load_from(path));
+            let (_, conn) = test_connection();
+            let reload = Reload::new(Source::from_args(&[]).unwrap(), build,
+                                     Arc::downgrade(&plugins),
+                                     prefixes.clone(),
+                                     Arc::new(RwLock::new(Logging::default())),
+                                     Arc::new(RwLock::new(Vec::new())), conn);
+            Setup { reload: reload, plugins: plugins, prefixes: prefixes }
+        }
+
+        fn apply(&self, text: &str) -> config::Result<()> {
+            self.reload.apply(Config::parse(text).unwrap()).map(|_| ())
+        }
+
+        fn names(&self) -> Vec<&'static str> {
+            self.plugins.read().unwrap().iter()
+                .map(|e| e.plugin().name())
+                .collect()
+        }
+
+        fn settings(&self, name: &str) -> String {
+            let (fake, conn) = test_connection();
+            let registry = self.plugins.read().unwrap();
+            find(&registry, name).unwrap().plugin()
+                .handle(&test_message(""), &conn).unwrap();
+            fake.sent()[0].1.clone()
+        }
+    }
+
+    #[test]
+    fn plugins_follow_the_config_order() {
+        let setup = Setup::new("order");
+        setup.apply("[plugins]\nenabled = [\"hug\", \"bully\"]").unwrap();
+        assert_eq!(setup.names(), vec!["hug", "bully"]);
+        setup.apply("[plugins]\nenabled = [\"anime\", \"hug\", \"bully\"]")
+            .unwrap();
+        assert_eq!(setup.names(), vec!["anime", "hug", "bully"]);
+        setup.apply("[plugins]\nenabled = [\"bully\", \"meme\", \"hug\"]")
+            .unwrap();
+        assert_eq!(setup.names(), vec!["bully", "meme", "hug"]);
+    }
+
+    #[test]
+    fn a_plugin_that_cannot_be_built_changes_nothing() {
+        let setup = Setup::new("unbuildable");
+        setup.apply("prefix = \"?\"\n\
+                     [plugins]\nenabled = [\"hug\"]").unwrap();
+        let err = setup.apply("prefix = \"%\"\n\
+                               [plugins]\nenabled = [\"bully\", \"meme\"]\n\
+                               [plugins.meme]\npath = \"unbuildable\"");
+        assert_eq!(err.unwrap_err().key, "plugins.meme.path");
+        assert_eq!(setup.names(), vec!["hug"]);
+        assert_eq!(setup.prefixes.default(), "?");
+    }
+
+    #[test]
+    fn a_failed_reconfigure_restores_the_old_settings() {
+        let setup = Setup::new("reconfigure");
+        setup.apply("prefix = \"?\"\n\
+                     [plugins]\nenabled = [\"meme\", \"anime\"]\n\
+                     [plugins.meme]\ncooldown = 1\n\
+                     [plugins.anime]\nquality = \"720p\"").unwrap();
+        let err = setup.apply("prefix = \"%\"\n\
+                               [plugins]\nenabled = [\"meme\", \"anime\", \
+                               \"hug\"]\n\
+                               [plugins.meme]\ncooldown = 2\n\
+                               [plugins.anime]\nquality = \"bad\"");
+        assert_eq!(err.unwrap_err().key, "plugins.anime.quality");
+        assert_eq!(setup.names(), vec!["meme", "anime"]);
+        assert_eq!(setup.settings("meme"), "Some(1) None");
+        assert_eq!(setup.settings("anime"), "None Some(\"720p\")");
+        assert_eq!(setup.prefixes.default(), "?");
+
+        // The failed config was not remembered, so this is a change.
+        setup.apply("[plugins]\nenabled = [\"meme\", \"anime\"]\n\
+                     [plugins.meme]\ncooldown = 2").unwrap();
+        assert_eq!(setup.settings("meme"), "Some(2) None");
+    }
+}
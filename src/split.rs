@@ -0,0 +1,218 @@
+/// Closes a code block left open at the end of a chunk.
+const CLOSE_FENCE: &'static str = "\n```";
+
+/// Splits `text` into chunks of at most `limit` characters. Text is split
+/// between lines where possible, then between words, and only splits words
+/// that do not fit in a chunk of their own. A code block that spans chunks is
+/// closed at the end of one and reopened, with its language, in the next.
+///
+/// With `max_chunks`, the N chunks past the cap are dropped and the last one
+/// kept ends with "…and N more". That chunk is cut short if the trailer
+/// would not fit otherwise.
+pub fn split_message(text: &str, limit: usize, max_chunks: Option<usize>)
+    -> Vec<String> {
+    let mut chunks = split(text, limit);
+    let max = match max_chunks {
+        Some(max) if max > 0 && chunks.len() > max => max,
+        _ => return chunks
+    };
+
+    let trailer = format!("\n…and {} more", chunks.len() - max);
+    chunks.truncate(max);
+    let last = chunks.pop().unwrap_or_default();
+    let room = limit.saturating_sub(trailer.chars().count());
+    let mut last = if last.chars().count() > room {
+        split(&last, room).into_iter().next().unwrap_or_default()
+    } else {
+        last
+    };
+    last.push_str(&trailer);
+    chunks.push(last);
+    chunks
+}
+
+fn split(text: &str, limit: usize) -> Vec<String> {
+    let mut splitter = Splitter {
+        limit: limit,
+        chunks: Vec::new(),
+        current: String::new(),
+        len: 0,
+        fresh: true,
+        fence: None
+    };
+    for line in text.split('\n') {
+        splitter.push_line(line);
+    }
+    splitter.finish()
+}
+
+struct Splitter {
+    limit: usize,
+    chunks: Vec<String>,
+    current: String,
+    // The length of `current` in characters.
+    len: usize,
+    // Whether `current` holds nothing but a reopened code fence.
+    fresh: bool,
+    // The fence of the code block open at the end of `current`, if any.
+    fence: Option<String>
+}
+
+impl Splitter {
+    fn push_line(&mut self, line: &str) {
+        let fence_after = next_fence(&self.fence, line);
+        if !self.fits(line, "\n", &fence_after) {
+            self.flush();
+        }
+        if self.fits(line, "\n", &fence_after) {
+            self.append(line, "\n");
+            self.fence = fence_after;
+            return
+        }
+
+        // The line does not fit in a chunk of its own, so split it on words,
+        // and split any word that is still too long on characters. The fence
+        // is followed word by word, so that a block opened partway through
+        // the line is closed and reopened like any other.
+        let mut sep = "\n";
+        for word in line.split(' ') {
+            let fence_after = next_fence(&self.fence, word);
+            if !self.fits(word, sep, &fence_after) {
+                self.flush();
+            }
+            if self.fits(word, sep, &fence_after) {
+                self.append(word, sep);
+            } else {
+                // Leave room to close a block open before or after the word.
+                let fence = fence_after.clone().or_else(|| self.fence.clone());
+                let mut char_sep = sep;
+                let mut buf = [0; 4];
+                for c in word.chars() {
+                    let c = c.encode_utf8(&mut buf);
+                    if !self.fits(c, char_sep, &fence) {
+                        self.flush();
+                    }
+                    self.append(c, char_sep);
+                    char_sep = "";
+                }
+            }
+            self.fence = fence_after;
+            sep = " ";
+        }
+    }
+
+    fn separator<'a>(&self, sep: &'a str) -> &'a str {
+        if self.current.is_empty() {
+            ""
+        } else if self.fresh {
+            "\n"
+        } else {
+            sep
+        }
+    }
+
+    fn fits(&self, piece: &str, sep: &str, fence: &Option<String>) -> bool {
+        let reserve = if fence.is_some() { CLOSE_FENCE.len() } else { 0 };
+        self.len + self.separator(sep).chars().count() + piece.chars().count()
+            + reserve <= self.limit
+    }
+
+    fn append(&mut self, piece: &str, sep: &str) {
+        let sep = self.separator(sep);
+        self.current.push_str(sep);
+        self.current.push_str(piece);
+        self.len += sep.chars().count() + piece.chars().count();
+        self.fresh = false;
+    }
+
+    /// Ends the current chunk, closing and reopening any open code block.
+    fn flush(&mut self) {
+        if self.fresh || self.current.is_empty() { return }
+        let mut chunk = String::new();
+        if let Some(ref fence) = self.fence {
+            self.current.push_str(CLOSE_FENCE);
+            chunk.push_str(fence);
+        }
+        self.len = chunk.chars().count();
+        self.chunks.push(::std::mem::replace(&mut self.current, chunk));
+        self.fresh = true;
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.flush();
+        self.chunks
+    }
+}
+
+/// Returns the fence of the code block open after `line`, or after a word of
+/// one, given the one open before it. An odd number of fences opens or closes
+/// a block.
+fn next_fence(fence: &Option<String>, line: &str) -> Option<String> {
+    if line.matches("```").count() % 2 == 0 {
+        return fence.clone()
+    }
+    if fence.is_some() {
+        return None
+    }
+
+    let line = line.trim_left();
+    if line.starts_with("```") {
+        let lang = line[3..].chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '-' || *c == '+')
+            .collect::<String>();
+        Some(format!("```{}", lang))
+    } else {
+        Some("```".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_message;
+
+    #[test]
+    fn splits_between_lines_then_words_then_characters() {
+        assert_eq!(split_message("aaa\nbbb\nccc", 7, None),
+                   vec!["aaa\nbbb", "ccc"]);
+        assert_eq!(split_message("one two three", 8, None),
+                   vec!["one two", "three"]);
+        assert_eq!(split_message("abcdefghij", 4, None),
+                   vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn reopens_a_code_block_in_the_next_chunk() {
+        let text = "```rust\nlet a = 1;\nlet b = 2;\n```";
+        assert_eq!(split_message(text, 24, None),
+                   vec!["```rust\nlet a = 1;\n```",
+                        "```rust\nlet b = 2;\n```"]);
+    }
+
+    #[test]
+    fn follows_a_code_block_opened_in_a_long_line() {
+        let text = "see ```py x = 1 and y = 2\nz = 3\n```";
+        assert_eq!(split_message(text, 20, None),
+                   vec!["see ```py x = 1\n```",
+                        "```py\nand y = 2\n```",
+                        "```py\nz = 3\n```"]);
+    }
+
+    #[test]
+    fn caps_the_chunks_with_a_trailer() {
+        let text = "0123456789\nabcdefghij\nABCDEFGHIJ\n9876543210";
+        assert_eq!(split_message(text, 40, None).len(), 2);
+        assert_eq!(split_message(text, 40, Some(5)).len(), 2);
+        assert_eq!(split_message(text, 40, Some(1)),
+                   vec!["0123456789\nabcdefghij\n…and 1 more"]);
+
+        // The trailer fits after the first chunk as it is.
+        let text = "0123456789\nabcdefghijabcdefghijabcdefghijabcde";
+        assert_eq!(split_message(text, 40, Some(1)),
+                   vec!["0123456789\n…and 1 more"]);
+    }
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        assert!(split_message("", 10, None).is_empty());
+    }
+}
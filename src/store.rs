@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap, HashMap};
+#[cfg(test)]
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use discord::model::ServerId;
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json;
+
+/// Reads per-server data saved with `save`. A file that does not exist yet
+/// holds nothing. Entries under a bad server id are skipped with a warning
+/// that calls them `what`.
+pub fn load<T: Decodable>(path: &Path, what: &str)
+    -> io::Result<HashMap<ServerId, T>> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut text)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(HashMap::new())
+        }
+        Err(err) => return Err(err)
+    };
+    let saved = json::decode::<BTreeMap<String, T>>(&text)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut servers = HashMap::new();
+    for (id, value) in saved {
+        match id.parse() {
+            Ok(id) => { servers.insert(ServerId(id), value); }
+            Err(_) => {
+                println!("[Warning] Ignoring {} for bad server id {}", what, id)
+            }
+        }
+    }
+    Ok(servers)
+}
+
+/// Saves per-server data as JSON keyed by server id. It is written to a file
+/// next to `path` first and then moved over it, so that a crash halfway
+/// leaves the old file as it was.
+pub fn save<T: Encodable>(path: &Path, servers: &HashMap<ServerId, T>)
+    -> io::Result<()> {
+    let saved = servers.iter()
+        .map(|(id, value)| (id.0.to_string(), value))
+        .collect::<BTreeMap<String, &T>>();
+    let text = json::encode(&saved)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    let temp = temp_path(path);
+    {
+        let mut file = File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp, path)
+}
+
+/// `path` with `.tmp` added to its file name.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// A path in the temp directory for a test to save to, with nothing saved
+/// there yet.
+#[cfg(test)]
+pub fn test_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(name);
+    let _ = fs::remove_file(&path);
+    path
+}
@@ -0,0 +1,431 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use discord::model::{ChannelId, ServerId};
+use discord::model::permissions::MANAGE_SERVER;
+use access::Access;
+use bot::{Connection, Message};
+use command::{Args, ArgKind, Command, Router};
+use error::Result;
+use executor::PluginExecutor;
+use plugin::Plugin;
+use store;
+
+/// Where the matrix is kept between runs.
+const FILE_PATH: &'static str = "toggles.json";
+/// The built-in plugins, which are needed to manage the others and so are
+/// on everywhere.
+const ALWAYS_ON: &'static [&'static str] =
+    &["help", "prefix", "access", "toggle", "reload"];
+
+/// Which plugins are on in one server, and in which of its channels that
+/// differs.
+#[derive(Clone, Debug, Default, RustcEncodable, RustcDecodable)]
+struct ServerToggles {
+    plugins: BTreeMap<String, bool>,
+    channels: BTreeMap<String, BTreeMap<String, bool>>
+}
+
+/// Where a plugin's state in a channel was decided.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Origin {
+    Default,
+    Server,
+    Channel
+}
+
+/// `Toggles` is the matrix of which plugins are on where. A plugin is on
+/// unless its server turned it off, and a channel can differ from its
+/// server either way. Private messages reach every plugin, and the built-in
+/// ones are never off. The matrix is saved to `toggles.json` whenever it
+/// changes.
+pub struct Toggles {
+    servers: RwLock<HashMap<ServerId, ServerToggles>>,
+    path: PathBuf
+}
+
+impl Toggles {
+    /// Loads the matrix saved by an earlier run, if any.
+    pub fn load() -> Toggles {
+        Toggles::load_from(PathBuf::from(FILE_PATH))
+    }
+
+    /// Like `load`, but keeps the matrix at `path`.
+    pub fn load_from(path: PathBuf) -> Toggles {
+        let servers = match store::load(&path, "toggles") {
+            Ok(servers) => servers,
+            Err(err) => {
+                println!("[Warning] Failed to load {}: {}", path.display(),
+                         err);
+                HashMap::new()
+            }
+        };
+        Toggles {
+            servers: RwLock::new(servers),
+            path: path
+        }
+    }
+
+    /// Whether `plugin` is on where `msg` was sent.
+    pub fn is_enabled(&self, msg: &Message, plugin: &str) -> bool {
+        self.is_enabled_in(msg.server_id(), Some(msg.channel_id()), plugin)
+    }
+
+    /// Whether `plugin` is on in a channel of a server, or in the server as
+    /// a whole without a channel. Outside of servers it always is.
+    pub fn is_enabled_in(&self, server: Option<ServerId>,
+                         channel: Option<ChannelId>, plugin: &str) -> bool {
+        if ALWAYS_ON.contains(&plugin) { return true }
+        let server = match server {
+            Some(server) => server,
+            None => return true
+        };
+        let servers = self.servers.read().unwrap();
+        let toggles = match servers.get(&server) {
+            Some(toggles) => toggles,
+            None => return true
+        };
+        channel.and_then(|c| toggles.channels.get(&c.0.to_string()))
+            .and_then(|plugins| plugins.get(plugin))
+            .or_else(|| toggles.plugins.get(plugin))
+            .cloned()
+            .unwrap_or(true)
+    }
+
+    /// Where `plugin`'s state in a channel was decided.
+    fn origin(&self, server: ServerId, channel: ChannelId, plugin: &str)
+        -> Origin {
+        let servers = self.servers.read().unwrap();
+        let toggles = match servers.get(&server) {
+            Some(toggles) => toggles,
+            None => return Origin::Default
+        };
+        if toggles.channels.get(&channel.0.to_string())
+            .map_or(false, |plugins| plugins.contains_key(plugin)) {
+            Origin::Channel
+        } else if toggles.plugins.contains_key(plugin) {
+            Origin::Server
+        } else {
+            Origin::Default
+        }
+    }
+
+    /// Turns `plugin` on or off in a whole server, undoing any channel
+    /// that differed, and saves the change.
+    pub fn set_server(&self, server: ServerId, plugin: &str, enabled: bool)
+        -> io::Result<()> {
+        let mut servers = self.servers.write().unwrap();
+        {
+            let toggles = servers.entry(server)
+                .or_insert_with(ServerToggles::default);
+            let empty = toggles.channels.iter_mut()
+                .filter_map(|(channel, plugins)| {
+                    plugins.remove(plugin);
+                    if plugins.is_empty() {
+                        Some(channel.clone())
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<String>>();
+            for channel in empty {
+                toggles.channels.remove(&channel);
+            }
+            if enabled {
+                toggles.plugins.remove(plugin);
+            } else {
+                toggles.plugins.insert(plugin.to_string(), false);
+            }
+        }
+        store::save(&self.path, &servers)
+    }
+
+    /// Turns `plugin` on or off in one channel of a server, and saves the
+    /// change.
+    pub fn set_channel(&self, server: ServerId, channel: ChannelId,
+                       plugin: &str, enabled: bool) -> io::Result<()> {
+        let mut servers = self.servers.write().unwrap();
+        {
+            let toggles = servers.entry(server)
+                .or_insert_with(ServerToggles::default);
+            let inherited = toggles.plugins.get(plugin).cloned()
+                .unwrap_or(true);
+            let key = channel.0.to_string();
+            if enabled == inherited {
+                let empty = match toggles.channels.get_mut(&key) {
+                    Some(plugins) => {
+                        plugins.remove(plugin);
+                        plugins.is_empty()
+                    }
+                    None => false
+                };
+                if empty {
+                    toggles.channels.remove(&key);
+                }
+            } else {
+                toggles.channels.entry(key).or_insert_with(BTreeMap::new)
+                    .insert(plugin.to_string(), enabled);
+            }
+        }
+        store::save(&self.path, &servers)
+    }
+}
+
+/// The built-in `!enable`, `!disable` and `!plugins` commands, which let
+/// server admins turn plugins on and off per server and per channel.
+pub struct TogglePlugin {
+    toggles: Arc<Toggles>,
+    plugins: Weak<RwLock<Vec<PluginExecutor>>>,
+    commands: Router<TogglePlugin>
+}
+
+impl TogglePlugin {
+    pub fn with_toggles(toggles: Arc<Toggles>,
+                        plugins: Weak<RwLock<Vec<PluginExecutor>>>)
+        -> TogglePlugin {
+        let admin = Access::Permission(MANAGE_SERVER);
+        TogglePlugin {
+            toggles: toggles,
+            plugins: plugins,
+            commands: Router::new()
+                .add(Command::new("plugins")
+                     .description("Shows which plugins are on in this channel"),
+                     TogglePlugin::list)
+                .add(Command::new("enable")
+                     .arg("plugin", ArgKind::Word)
+                     .arg("server", ArgKind::Literal("server"))
+                     .description("Turns a plugin on in the whole server")
+                     .access(admin.clone()),
+                     TogglePlugin::enable_server)
+                .add(Command::new("enable")
+                     .arg("plugin", ArgKind::Word)
+                     .optional("channel", ArgKind::Channel)
+                     .description("Turns a plugin on in a channel")
+                     .access(admin.clone()),
+                     TogglePlugin::enable_channel)
+                .add(Command::new("disable")
+                     .arg("plugin", ArgKind::Word)
+                     .arg("server", ArgKind::Literal("server"))
+                     .description("Turns a plugin off in the whole server")
+                     .access(admin.clone()),
+                     TogglePlugin::disable_server)
+                .add(Command::new("disable")
+                     .arg("plugin", ArgKind::Word)
+                     .optional("channel", ArgKind::Channel)
+                     .description("Turns a plugin off in a channel")
+                     .access(admin),
+                     TogglePlugin::disable_channel)
+        }
+    }
+
+    fn list(&self, _args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        let server = match msg.server_id() {
+            Some(server) => server,
+            None => {
+                conn.reply(msg, "Every plugin is on in private messages")?;
+                return Ok(())
+            }
+        };
+        let plugins = match self.plugins.upgrade() {
+            Some(plugins) => plugins,
+            None => return Ok(())
+        };
+        let mut text = format!("Plugins in <#{}>:\n", msg.channel_id().0);
+        for e in plugins.read().unwrap().iter() {
+            let name = e.plugin().name();
+            let state = if self.toggles.is_enabled(msg, name) {
+                "on"
+            } else {
+                "off"
+            };
+            let origin = match self.toggles.origin(server, msg.channel_id(),
+                                                   name) {
+                Origin::Channel => " (this channel)",
+                Origin::Server => " (server)",
+                Origin::Default => ""
+            };
+            text.push_str(&format!("`{}` — {}{}\n", name, state, origin));
+        }
+        conn.reply_split(msg, &text, None)
+    }
+
+    fn enable_server(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        self.update(args, msg, conn, true, false)
+    }
+
+    fn enable_channel(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        self.update(args, msg, conn, true, true)
+    }
+
+    fn disable_server(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        self.update(args, msg, conn, false, false)
+    }
+
+    fn disable_channel(&self, args: &Args, msg: &Message, conn: &Connection)
+        -> Result<()> {
+        self.update(args, msg, conn, false, true)
+    }
+
+    fn update(&self, args: &Args, msg: &Message, conn: &Connection,
+              enabled: bool, in_channel: bool) -> Result<()> {
+        let server = match msg.server_id() {
+            Some(server) => server,
+            None => {
+                conn.reply(msg, "Plugins can only be turned on and off in \
+                                 a server")?;
+                return Ok(())
+            }
+        };
+        let name = args.str("plugin").unwrap();
+        if ALWAYS_ON.contains(&name) {
+            conn.reply(msg, &format!("`{}` is always on", name))?;
+            return Ok(())
+        }
+        let known = self.plugins.upgrade().map_or(false, |plugins| {
+            plugins.read().unwrap().iter().any(|e| e.plugin().name() == name)
+        });
+        if !known {
+            conn.reply(msg, &format!("There is no plugin called `{}`. Use \
+                                      `{}plugins` to list them", name,
+                                     msg.prefix()))?;
+            return Ok(())
+        }
+
+        let state = if enabled { "on" } else { "off" };
+        let (result, text) = if in_channel {
+            let channel = args.channel("channel")
+                .unwrap_or_else(|| msg.channel_id());
+            (self.toggles.set_channel(server, channel, name, enabled),
+             format!("`{}` is now {} in <#{}>", name, state, channel.0))
+        } else {
+            (self.toggles.set_server(server, name, enabled),
+             format!("`{}` is now {} in every channel of this server", name,
+                     state))
+        };
+        if let Err(err) = result {
+            println!("[Error] Failed to save {}: {}",
+                     self.toggles.path.display(), err);
+        }
+        conn.reply(msg, &text)?;
+        Ok(())
+    }
+}
+
+impl Plugin for TogglePlugin {
+    /// Creates a `TogglePlugin` with a matrix of its own that knows of no
+    /// plugins. Use the one the `Bot` registers instead.
+    fn new() -> Box<Plugin> {
+        Box::new(TogglePlugin::with_toggles(Arc::new(Toggles::load()),
+                                            Weak::new()))
+    }
+
+    fn name(&self) -> &'static str {
+        "toggle"
+    }
+
+    fn description(&self) -> &'static str {
+        "Turns plugins on and off per channel"
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.commands()
+    }
+
+    fn is_match(&self, msg: &Message) -> bool {
+        self.commands.is_match(msg)
+    }
+
+    fn handle(&self, msg: &Message, conn: &Connection) -> Result<()> {
+        self.commands.dispatch(self, msg, conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Weak};
+    use discord::model::{ChannelId, ServerId};
+    use bot::Message;
+    use plugin::Plugin;
+    use store;
+    use transport::{fake_message, fake_user, test_connection};
+    use super::{Origin, TogglePlugin, Toggles};
+
+    const SERVER: ServerId = ServerId(5);
+
+    fn toggles(name: &str) -> Toggles {
+        Toggles::load_from(store::test_path(&format!("toggles-{}.json", name)))
+    }
+
+    fn on(toggles: &Toggles, channel: Option<u64>) -> bool {
+        toggles.is_enabled_in(Some(SERVER), channel.map(ChannelId), "meme")
+    }
+
+    #[test]
+    fn a_channel_can_differ_from_its_server() {
+        let toggles = toggles("channel");
+        assert!(on(&toggles, Some(1)));
+        toggles.set_server(SERVER, "meme", false).unwrap();
+        toggles.set_channel(SERVER, ChannelId(2), "meme", true).unwrap();
+
+        assert!(!on(&toggles, None));
+        assert!(!on(&toggles, Some(1)));
+        assert!(on(&toggles, Some(2)));
+        assert!(toggles.is_enabled_in(Some(ServerId(6)), None, "meme"));
+        assert!(toggles.is_enabled_in(None, Some(ChannelId(1)), "meme"));
+        assert_eq!(toggles.origin(SERVER, ChannelId(1), "meme"),
+                   Origin::Server);
+        assert_eq!(toggles.origin(SERVER, ChannelId(2), "meme"),
+                   Origin::Channel);
+
+        // Matching the server again drops the channel's own setting.
+        toggles.set_channel(SERVER, ChannelId(2), "meme", false).unwrap();
+        assert_eq!(toggles.origin(SERVER, ChannelId(2), "meme"),
+                   Origin::Server);
+    }
+
+    #[test]
+    fn the_server_setting_undoes_the_channels() {
+        let toggles = toggles("server");
+        toggles.set_channel(SERVER, ChannelId(1), "meme", false).unwrap();
+        toggles.set_channel(SERVER, ChannelId(2), "hug", false).unwrap();
+        toggles.set_server(SERVER, "meme", true).unwrap();
+
+        assert!(on(&toggles, Some(1)));
+        assert_eq!(toggles.origin(SERVER, ChannelId(1), "meme"),
+                   Origin::Default);
+        assert!(!toggles.is_enabled_in(Some(SERVER), Some(ChannelId(2)),
+                                       "hug"));
+    }
+
+    #[test]
+    fn toggles_are_kept_between_runs() {
+        let path = store::test_path("toggles-kept.json");
+        Toggles::load_from(path.clone())
+            .set_channel(SERVER, ChannelId(1), "meme", false).unwrap();
+        assert!(!on(&Toggles::load_from(path), Some(1)));
+    }
+
+    #[test]
+    fn the_built_in_plugins_are_always_on() {
+        let toggles = Arc::new(toggles("built-in"));
+        toggles.set_server(SERVER, "help", false).unwrap();
+        assert!(toggles.is_enabled_in(Some(SERVER), None, "help"));
+
+        let plugin = TogglePlugin::with_toggles(toggles, Weak::new());
+        let (fake, conn) = test_connection();
+        for text in &["!disable toggle server", "!disable help"] {
+            let msg = fake_message(ChannelId(1), fake_user(1, "user"), text);
+            plugin.handle(&Message::with_server(msg, Some(SERVER)), &conn)
+                .unwrap();
+        }
+        let sent = fake.sent().into_iter().map(|(_, text)| text)
+            .collect::<Vec<String>>();
+        assert_eq!(sent, vec!["<@1> `toggle` is always on",
+                              "<@1> `help` is always on"]);
+    }
+}
@@ -0,0 +1,444 @@
+#[cfg(test)]
+use std::sync::Arc;
+use std::sync::{Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use chrono::UTC;
+use discord::{Discord, Connection as DiscordConnection, Error};
+use discord::model::{Message as DiscordMessage, MessageType, Event, ChannelId,
+                     MessageId, User, UserId, ReadyEvent, ReactionEmoji,
+                     CurrentUser, Role, RoleId, Permissions};
+#[cfg(test)]
+use bot::{Connection, Message};
+use embed::Embed;
+
+/// Ids handed out to messages created by `fake_message`.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// A `Transport` is what a `Connection` talks to. The real implementation is
+/// `DiscordTransport`; `FakeTransport` lets plugins run without a live token.
+pub trait Transport: Send + Sync {
+    /// Opens a gateway session, returning its event stream and the
+    /// `ReadyEvent` that started it.
+    fn connect(&self) -> Result<(Box<EventStream>, ReadyEvent), Error>;
+
+    /// Sends a text message to a channel.
+    fn send_message(&self, channel: ChannelId, text: &str)
+        -> Result<DiscordMessage, Error>;
+
+    /// Sends an embed, with optional text above it, to a channel.
+    fn send_embed(&self, channel: ChannelId, text: &str, embed: &Embed)
+        -> Result<DiscordMessage, Error>;
+
+    /// Uploads a file, with optional text above it, to a channel.
+    fn send_file(&self, channel: ChannelId, text: &str, file: &[u8],
+                 filename: &str) -> Result<DiscordMessage, Error>;
+
+    /// Replaces the text of a message the bot sent.
+    fn edit_message(&self, channel: ChannelId, message: MessageId, text: &str)
+        -> Result<DiscordMessage, Error>;
+
+    fn delete_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error>;
+
+    /// Adds the bot's own reaction to a message.
+    fn add_reaction(&self, channel: ChannelId, message: MessageId,
+                    emoji: &ReactionEmoji) -> Result<(), Error>;
+
+    /// Removes a user's reaction from a message, or the bot's own if `user`
+    /// is `None`.
+    fn remove_reaction(&self, channel: ChannelId, message: MessageId,
+                       user: Option<UserId>, emoji: &ReactionEmoji)
+        -> Result<(), Error>;
+
+    fn pin_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error>;
+
+    fn unpin_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error>;
+
+    /// Shows the bot as typing in a channel for about ten seconds, or until
+    /// it next sends a message there.
+    fn broadcast_typing(&self, channel: ChannelId) -> Result<(), Error>;
+}
+
+/// A stream of gateway events belonging to one session. It is read on its
+/// own thread, hence `Send`.
+pub trait EventStream: Send {
+    fn recv_event(&mut self) -> Result<Event, Error>;
+
+    /// Closes the session cleanly.
+    fn shutdown(self: Box<Self>) -> Result<(), Error>;
+}
+
+impl EventStream for DiscordConnection {
+    fn recv_event(&mut self) -> Result<Event, Error> {
+        DiscordConnection::recv_event(self)
+    }
+
+    fn shutdown(self: Box<Self>) -> Result<(), Error> {
+        DiscordConnection::shutdown(*self)
+    }
+}
+
+/// A `Transport` backed by the Discord REST and gateway APIs.
+pub struct DiscordTransport {
+    inner: Mutex<Discord>
+}
+
+impl DiscordTransport {
+    pub fn new(discord: Discord) -> DiscordTransport {
+        DiscordTransport {
+            inner: Mutex::new(discord)
+        }
+    }
+}
+
+impl Transport for DiscordTransport {
+    fn connect(&self) -> Result<(Box<EventStream>, ReadyEvent), Error> {
+        let (connection, ready) = self.inner.lock().unwrap().connect()?;
+        Ok((Box::new(connection), ready))
+    }
+
+    fn send_message(&self, channel: ChannelId, text: &str)
+        -> Result<DiscordMessage, Error> {
+        self.inner.lock().unwrap().send_message(channel, text, "", false)
+    }
+
+    fn send_embed(&self, channel: ChannelId, text: &str, embed: &Embed)
+        -> Result<DiscordMessage, Error> {
+        self.inner.lock().unwrap().send_embed(channel, text, |e| embed.build(e))
+    }
+
+    fn send_file(&self, channel: ChannelId, text: &str, file: &[u8],
+                 filename: &str) -> Result<DiscordMessage, Error> {
+        self.inner.lock().unwrap().send_file(channel, text, file, filename)
+    }
+
+    fn edit_message(&self, channel: ChannelId, message: MessageId, text: &str)
+        -> Result<DiscordMessage, Error> {
+        self.inner.lock().unwrap().edit_message(channel, message, text)
+    }
+
+    fn delete_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        self.inner.lock().unwrap().delete_message(channel, message)
+    }
+
+    fn add_reaction(&self, channel: ChannelId, message: MessageId,
+                    emoji: &ReactionEmoji) -> Result<(), Error> {
+        self.inner.lock().unwrap().add_reaction(channel, message, emoji.clone())
+    }
+
+    fn remove_reaction(&self, channel: ChannelId, message: MessageId,
+                       user: Option<UserId>, emoji: &ReactionEmoji)
+        -> Result<(), Error> {
+        self.inner.lock().unwrap()
+            .delete_reaction(channel, message, user, emoji.clone())
+    }
+
+    fn pin_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        self.inner.lock().unwrap().pin_message(channel, message)
+    }
+
+    fn unpin_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        self.inner.lock().unwrap().unpin_message(channel, message)
+    }
+
+    fn broadcast_typing(&self, channel: ChannelId) -> Result<(), Error> {
+        self.inner.lock().unwrap().broadcast_typing(channel)
+    }
+}
+
+/// Something a `FakeTransport` was asked to do to an existing message.
+#[derive(Clone, Debug)]
+pub enum Action {
+    Edit(ChannelId, MessageId, String),
+    Delete(ChannelId, MessageId),
+    React(ChannelId, MessageId, ReactionEmoji),
+    Unreact(ChannelId, MessageId, Option<UserId>, ReactionEmoji),
+    Pin(ChannelId, MessageId),
+    Unpin(ChannelId, MessageId),
+    Typing(ChannelId)
+}
+
+/// An in-memory `Transport` that records every outgoing message and replays
+/// events injected with `inject` once connected.
+pub struct FakeTransport {
+    user: User,
+    ready: Mutex<Option<ReadyEvent>>,
+    sent: Mutex<Vec<(ChannelId, String)>>,
+    embeds: Mutex<Vec<(ChannelId, Embed)>>,
+    files: Mutex<Vec<(ChannelId, String, Vec<u8>)>>,
+    actions: Mutex<Vec<Action>>,
+    // How long the next message sent is rate limited for, if it is.
+    rate_limit: Mutex<Option<u64>>,
+    sender: Mutex<Option<mpsc::Sender<Event>>>,
+    receiver: Mutex<Option<mpsc::Receiver<Event>>>
+}
+
+impl FakeTransport {
+    /// Creates a `FakeTransport` that can send but not connect. This is all
+    /// that is needed to call `Plugin::handle` directly.
+    pub fn new() -> FakeTransport {
+        let (tx, rx) = mpsc::channel();
+        FakeTransport {
+            user: fake_user(0, "bot"),
+            ready: Mutex::new(None),
+            sent: Mutex::new(Vec::new()),
+            embeds: Mutex::new(Vec::new()),
+            files: Mutex::new(Vec::new()),
+            actions: Mutex::new(Vec::new()),
+            rate_limit: Mutex::new(None),
+            sender: Mutex::new(Some(tx)),
+            receiver: Mutex::new(Some(rx))
+        }
+    }
+
+    /// Creates a `FakeTransport` whose `connect` succeeds with `ready`.
+    pub fn with_ready(ready: ReadyEvent) -> FakeTransport {
+        let fake = FakeTransport::new();
+        *fake.ready.lock().unwrap() = Some(ready);
+        fake
+    }
+
+    /// Queues an event to be returned by the event stream.
+    pub fn inject(&self, event: Event) {
+        if let Some(ref tx) = *self.sender.lock().unwrap() {
+            let _ = tx.send(event);
+        }
+    }
+
+    /// Queues a `MessageCreate` event as if `author` had said `content`.
+    pub fn inject_message(&self, channel: ChannelId, author: User,
+                          content: &str) {
+        self.inject(Event::MessageCreate(fake_message(channel, author, content)));
+    }
+
+    /// Closes the event stream once all injected events have been received.
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+
+    /// Makes the next message sent fail as if Discord had asked to wait `ms`
+    /// before sending it.
+    pub fn rate_limit_next(&self, ms: u64) {
+        *self.rate_limit.lock().unwrap() = Some(ms);
+    }
+
+    /// Returns every message sent so far, in order, with its channel. The
+    /// text sent along with an embed is included.
+    pub fn sent(&self) -> Vec<(ChannelId, String)> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Returns every embed sent so far, in order, with its channel.
+    pub fn embeds(&self) -> Vec<(ChannelId, Embed)> {
+        self.embeds.lock().unwrap().clone()
+    }
+
+    /// Returns every file uploaded so far, in order, with its channel and
+    /// filename.
+    pub fn files(&self) -> Vec<(ChannelId, String, Vec<u8>)> {
+        self.files.lock().unwrap().clone()
+    }
+
+    /// Returns every edit, deletion, reaction, pin and typing broadcast so
+    /// far, in order.
+    pub fn actions(&self) -> Vec<Action> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    /// Forgets all recorded messages.
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+        self.embeds.lock().unwrap().clear();
+        self.files.lock().unwrap().clear();
+        self.actions.lock().unwrap().clear();
+    }
+
+    fn record(&self, action: Action) -> Result<(), Error> {
+        self.actions.lock().unwrap().push(action);
+        Ok(())
+    }
+}
+
+impl Transport for FakeTransport {
+    fn connect(&self) -> Result<(Box<EventStream>, ReadyEvent), Error> {
+        let ready = match *self.ready.lock().unwrap() {
+            Some(ref ready) => ready.clone(),
+            None => return Err(Error::Other("Fake transport has no ReadyEvent"))
+        };
+        match self.receiver.lock().unwrap().take() {
+            Some(rx) => Ok((Box::new(FakeEventStream { inner: rx }), ready)),
+            None => Err(Error::Other("Fake transport is already connected"))
+        }
+    }
+
+    fn send_message(&self, channel: ChannelId, text: &str)
+        -> Result<DiscordMessage, Error> {
+        if let Some(ms) = self.rate_limit.lock().unwrap().take() {
+            return Err(Error::RateLimited(ms))
+        }
+        self.sent.lock().unwrap().push((channel, text.to_string()));
+        Ok(fake_message(channel, self.user.clone(), text))
+    }
+
+    fn send_embed(&self, channel: ChannelId, text: &str, embed: &Embed)
+        -> Result<DiscordMessage, Error> {
+        self.embeds.lock().unwrap().push((channel, embed.clone()));
+        self.send_message(channel, text)
+    }
+
+    fn send_file(&self, channel: ChannelId, text: &str, file: &[u8],
+                 filename: &str) -> Result<DiscordMessage, Error> {
+        self.files.lock().unwrap()
+            .push((channel, filename.to_string(), file.to_vec()));
+        self.send_message(channel, text)
+    }
+
+    fn edit_message(&self, channel: ChannelId, message: MessageId, text: &str)
+        -> Result<DiscordMessage, Error> {
+        self.record(Action::Edit(channel, message, text.to_string()))?;
+        let mut edited = fake_message(channel, self.user.clone(), text);
+        edited.id = message;
+        Ok(edited)
+    }
+
+    fn delete_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        self.record(Action::Delete(channel, message))
+    }
+
+    fn add_reaction(&self, channel: ChannelId, message: MessageId,
+                    emoji: &ReactionEmoji) -> Result<(), Error> {
+        self.record(Action::React(channel, message, emoji.clone()))
+    }
+
+    fn remove_reaction(&self, channel: ChannelId, message: MessageId,
+                       user: Option<UserId>, emoji: &ReactionEmoji)
+        -> Result<(), Error> {
+        self.record(Action::Unreact(channel, message, user, emoji.clone()))
+    }
+
+    fn pin_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        self.record(Action::Pin(channel, message))
+    }
+
+    fn unpin_message(&self, channel: ChannelId, message: MessageId)
+        -> Result<(), Error> {
+        self.record(Action::Unpin(channel, message))
+    }
+
+    fn broadcast_typing(&self, channel: ChannelId) -> Result<(), Error> {
+        self.record(Action::Typing(channel))
+    }
+}
+
+struct FakeEventStream {
+    inner: mpsc::Receiver<Event>
+}
+
+impl EventStream for FakeEventStream {
+    fn recv_event(&mut self) -> Result<Event, Error> {
+        self.inner.recv().map_err(|_| {
+            Error::Closed(None, "Fake transport closed".to_string())
+        })
+    }
+
+    fn shutdown(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Creates a user that is not a bot.
+pub fn fake_user(id: u64, name: &str) -> User {
+    User {
+        id: UserId(id),
+        name: name.to_string(),
+        discriminator: 0,
+        avatar: None,
+        bot: false
+    }
+}
+
+/// Creates a server role with no permissions of its own.
+pub fn fake_role(id: u64, name: &str) -> Role {
+    Role {
+        id: RoleId(id),
+        name: name.to_string(),
+        color: 0,
+        hoist: false,
+        managed: false,
+        position: 0,
+        mentionable: false,
+        permissions: Permissions::empty()
+    }
+}
+
+/// Creates the `ReadyEvent` of a session logged in as `user`, in no servers.
+pub fn fake_ready(user: User) -> ReadyEvent {
+    ReadyEvent {
+        version: 6,
+        user: CurrentUser {
+            id: user.id,
+            username: user.name,
+            discriminator: user.discriminator,
+            avatar: None,
+            email: None,
+            verified: true,
+            bot: true,
+            mfa_enabled: false
+        },
+        session_id: "fake".to_string(),
+        user_settings: None,
+        read_state: None,
+        private_channels: Vec::new(),
+        presences: Vec::new(),
+        relationships: Vec::new(),
+        servers: Vec::new(),
+        user_server_settings: None,
+        tutorial: None,
+        trace: Vec::new(),
+        notes: None,
+        shard: None
+    }
+}
+
+/// Creates a plain text message as if `author` had sent it to `channel` now.
+pub fn fake_message(channel: ChannelId, author: User, content: &str)
+    -> DiscordMessage {
+    DiscordMessage {
+        id: MessageId(NEXT_ID.fetch_add(1, Ordering::SeqCst) as u64),
+        channel_id: channel,
+        content: content.to_string(),
+        nonce: None,
+        tts: false,
+        timestamp: UTC::now().to_rfc3339(),
+        edited_timestamp: None,
+        pinned: false,
+        kind: MessageType::Regular,
+        author: author,
+        mention_everyone: false,
+        mentions: Vec::new(),
+        mention_roles: Vec::new(),
+        reactions: Vec::new(),
+        attachments: Vec::new(),
+        embeds: Vec::new()
+    }
+}
+
+/// A `Connection` over a new `FakeTransport`, for tests.
+#[cfg(test)]
+pub fn test_connection() -> (Arc<FakeTransport>, Connection) {
+    let fake = Arc::new(FakeTransport::new());
+    (fake.clone(), Connection::new(fake))
+}
+
+/// A message from user 1, called "user", in channel 1, for tests.
+#[cfg(test)]
+pub fn test_message(content: &str) -> Message {
+    Message::new(fake_message(ChannelId(1), fake_user(1, "user"), content))
+}
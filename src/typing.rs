@@ -0,0 +1,102 @@
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
+use discord::model::ChannelId;
+use bot::Connection;
+
+/// Discord shows a typing indicator for ten seconds, so refresh it a little
+/// sooner than that.
+const REFRESH_SECS: u64 = 8;
+
+struct Shared {
+    stopped: Mutex<bool>,
+    // Signalled when the indicator is stopped.
+    changed: Condvar
+}
+
+/// Shows the bot as typing in a channel until the `Typing` is dropped.
+///
+/// Discord has no way to take the indicator down early, so it lingers for up
+/// to `REFRESH_SECS` after the drop unless the bot sends a message, which
+/// clears it.
+pub struct Typing {
+    shared: Arc<Shared>
+}
+
+impl Typing {
+    pub fn start(conn: &Connection, channel: ChannelId) -> Typing {
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            changed: Condvar::new()
+        });
+
+        let shared_1 = shared.clone();
+        let conn = conn.clone();
+        let spawned = thread::Builder::new()
+            .name("typing".to_string())
+            .spawn(move || refresh(shared_1, conn, channel));
+        if let Err(err) = spawned {
+            println!("[Warning] Failed to spawn typing indicator: {}", err);
+        }
+
+        Typing {
+            shared: shared
+        }
+    }
+}
+
+impl Drop for Typing {
+    fn drop(&mut self) {
+        // The lock is only poisoned if the refresh thread panicked, in which
+        // case there is nothing left to stop.
+        if let Ok(mut stopped) = self.shared.stopped.lock() {
+            *stopped = true;
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+fn refresh(shared: Arc<Shared>, conn: Connection, channel: ChannelId) {
+    loop {
+        // Broadcast without holding the lock so that dropping the `Typing`
+        // never waits on Discord.
+        if let Err(err) = conn.broadcast_typing(channel) {
+            println!("[Warning] Failed to show typing in {}: {}",
+                     channel.0, err);
+            return
+        }
+        let stopped = shared.stopped.lock().unwrap();
+        if *stopped { return }
+        let stopped = shared.changed
+            .wait_timeout(stopped, Duration::from_secs(REFRESH_SECS))
+            .unwrap().0;
+        if *stopped { return }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use discord::model::ChannelId;
+    use transport::{Action, test_connection};
+    use super::Typing;
+
+    #[test]
+    fn shows_typing_in_the_channel() {
+        let (fake, conn) = test_connection();
+        let typing = Typing::start(&conn, ChannelId(3));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fake.actions().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(typing);
+
+        let actions = fake.actions();
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            Action::Typing(channel) => assert_eq!(channel, ChannelId(3)),
+            ref other => panic!("expected typing, got {:?}", other)
+        }
+    }
+}